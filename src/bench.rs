@@ -0,0 +1,217 @@
+use crate::cli::BenchArgs;
+use crate::{
+    Client, ClientsDb, ExchangeOperation, FsyncPolicy, Journal, JournalBase, JournalDb, Operation,
+    Order, OrderType, OrdersDb, Price, Request, Response, Result,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Clients trading every ticker, so settlements on different tickers keep
+/// meeting on the same clients.
+const CLIENTS: usize = 4;
+
+/// Matches the same generated orders with each thread count, every thread
+/// owning a share of the tickers, and prints the throughput. With a
+/// journal it does it again with every order appended to the journal
+/// first, the way the server runs requests.
+pub async fn run(args: &BenchArgs) -> Result<()> {
+    println!("threads\torders/s\tspeedup");
+    run_all(args, None).await?;
+    if let Some(path) = &args.journal {
+        println!("journaled, fsync {:?}", args.fsync);
+        run_all(args, Some((path, args.fsync))).await?;
+    }
+    Ok(())
+}
+
+async fn run_all(args: &BenchArgs, journal: Option<(&Path, FsyncPolicy)>) -> Result<()> {
+    let mut base = None;
+    for &threads in &args.threads {
+        let journal = match journal {
+            Some((path, policy)) => {
+                let _ = std::fs::remove_file(path);
+                let (journal, _) = Journal::open(path, policy)?;
+                Some(Arc::new(JournalBase::new(journal)))
+            }
+            None => None,
+        };
+        let (elapsed, _, _) = run_once(args.tickers, args.orders, threads.max(1), journal).await?;
+        let total = (args.tickers * args.orders) as f64;
+        let rate = total / elapsed.as_secs_f64().max(f64::EPSILON);
+        let base = *base.get_or_insert(rate);
+        println!("{}\t{:.0}\t{:.2}", threads, rate, rate / base);
+    }
+    Ok(())
+}
+
+/// Lists `tickers` tickers, matches `orders` orders on each of them with
+/// `threads` threads and returns the time spent matching. The threads
+/// run their own runtimes, so the caller's runtime only does the setup.
+async fn run_once(
+    tickers: usize,
+    orders: usize,
+    threads: usize,
+    journal: Option<JournalDb>,
+) -> Result<(Duration, OrdersDb, ClientsDb)> {
+    let orders_db = OrdersDb::default();
+    let clients_db = ClientsDb::default();
+    let names: Vec<String> = (0..tickers).map(|ticker| format!("T{}", ticker)).collect();
+    for name in &names {
+        ExchangeOperation::add_ticker(orders_db.clone(), name).await?;
+    }
+    for client in 0..CLIENTS {
+        let mut client = Client::new(&format!("C{}", client), Price::units(u32::MAX));
+        for name in &names {
+            client.deposit_stock(name, u32::MAX / 2)?;
+        }
+        ExchangeOperation::add_client(clients_db.clone(), client).await?;
+    }
+
+    let mut work: Vec<Vec<Vec<Order>>> = vec![Vec::new(); threads];
+    for (ticker, name) in names.iter().enumerate() {
+        work[ticker % threads].push(generate(name, orders, ticker as u64));
+    }
+    let start = Instant::now();
+    let results: Vec<Result<()>> = thread::scope(|scope| {
+        let workers: Vec<_> = work
+            .into_iter()
+            .map(|books| {
+                let orders_db = orders_db.clone();
+                let clients_db = clients_db.clone();
+                let journal = journal.clone();
+                scope.spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+                    runtime.block_on(trade(orders_db, clients_db, journal, books))
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    let elapsed = start.elapsed();
+    results.into_iter().collect::<Result<()>>()?;
+    Ok((elapsed, orders_db, clients_db))
+}
+
+/// Takes one order of each ticker in turn, keeping every ticker's own
+/// order.
+async fn trade(
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    journal: Option<JournalDb>,
+    books: Vec<Vec<Order>>,
+) -> Result<()> {
+    let mut books: Vec<_> = books.into_iter().map(Vec::into_iter).collect();
+    loop {
+        let mut placed = false;
+        for orders in books.iter_mut() {
+            if let Some(order) = orders.next() {
+                match &journal {
+                    Some(journal) => {
+                        let request = place_order(&order);
+                        let response = journal
+                            .execute(request, orders_db.clone(), clients_db.clone())
+                            .await;
+                        if let Response::Error { error, .. } = response {
+                            return Err(error);
+                        }
+                    }
+                    None => {
+                        ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+                            .await?;
+                    }
+                }
+                placed = true;
+            }
+        }
+        if !placed {
+            return Ok(());
+        }
+    }
+}
+
+/// The request placing a generated limit order.
+fn place_order(order: &Order) -> Request {
+    Request::PlaceOrder {
+        client: order.get_client().to_string(),
+        side: order.get_operation(),
+        ticker: order.get_ticker().to_string(),
+        price: order.get_price(),
+        amount: order.get_amount(),
+        order_type: OrderType::Limit,
+        stop_price: Price::default(),
+        display_amount: None,
+        time_in_force: order.get_time_in_force(),
+        self_trade_prevention: order.get_self_trade_prevention(),
+        post_only: order.get_post_only(),
+    }
+}
+
+/// Random limit orders around 100, the same for the same seed.
+fn generate(ticker: &str, count: usize, seed: u64) -> Vec<Order> {
+    let mut state = seed;
+    (0..count)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let random = (state >> 33) as u32;
+            let operation = match random & 1 {
+                0 => Operation::Buy,
+                _ => Operation::Sell,
+            };
+            let price = Price::units(95 + (random >> 1) % 11);
+            let amount = 1 + (random >> 5) % 10;
+            let client = format!("C{}", (random >> 9) as usize % CLIENTS);
+            Order::new(&client, operation, ticker, price, amount)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_threads_do_not_change_the_outcome() {
+        let (_, single_orders, single_clients) = run_once(6, 300, 1, None).await.unwrap();
+        let (_, orders_db, clients_db) = run_once(6, 300, 3, None).await.unwrap();
+        assert_eq!(orders_db.orders_count(), single_orders.orders_count());
+        for (client, single) in clients_db.get_all().iter().zip(single_clients.get_all()) {
+            assert_eq!(client.get_name(), single.get_name());
+            assert_eq!(client.get_balance(), single.get_balance());
+            assert_eq!(client.get_reserved_balance(), single.get_reserved_balance());
+            for ticker in orders_db.get_tickers() {
+                assert_eq!(
+                    client.get_amount_of_stock(&ticker),
+                    single.get_amount_of_stock(&ticker)
+                );
+                assert_eq!(
+                    client.get_reserved_stock(&ticker),
+                    single.get_reserved_stock(&ticker)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_journaled_run_keeps_the_outcome() {
+        let path = std::env::temp_dir().join("victorparamonov_bench.journal");
+        let _ = std::fs::remove_file(&path);
+        let (journal, _) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let journal = Arc::new(JournalBase::new(journal));
+        let (_, single_orders, single_clients) = run_once(4, 200, 1, None).await.unwrap();
+        let (_, orders_db, clients_db) = run_once(4, 200, 2, Some(journal.clone())).await.unwrap();
+        assert_eq!(journal.lock().await.get_last_sequence(), 800);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(orders_db.orders_count(), single_orders.orders_count());
+        assert_eq!(clients_db.get_all(), single_clients.get_all());
+    }
+}
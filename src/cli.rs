@@ -0,0 +1,235 @@
+use crate::FsyncPolicy;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(about = "Simple matching engine")]
+pub struct Cli {
+    /// -v prints timings, -vv also every rejected order
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Without a subcommand the default tapes are replayed
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Replay the orders tape and write the clients' final balances
+    Replay(ReplayArgs),
+    /// Check that the tapes parse, without matching anything
+    Validate(TapeArgs),
+    /// Replay the orders tape and print the resting order book
+    Book(BookArgs),
+    /// Answer JSON Lines requests, one response line per request
+    Requests(RequestsArgs),
+    /// Accept JSON Lines order-entry sessions over TCP
+    Serve(ServeArgs),
+    /// Measure matching throughput with tickers spread over threads
+    Bench(BenchArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TapeArgs {
+    #[arg(long, default_value = "./Clients.txt")]
+    pub clients: PathBuf,
+
+    #[arg(long, default_value = "./Orders.txt")]
+    pub orders: PathBuf,
+
+    /// Collect the whole orders tape in an opening auction per ticker and
+    /// uncross it at the end, instead of matching from the first line
+    #[arg(long)]
+    pub opening_auction: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ReplayArgs {
+    #[command(flatten)]
+    pub tapes: TapeArgs,
+
+    #[arg(short, long, default_value = "result.txt")]
+    pub output: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Expected text report to diff the result against
+    #[arg(long)]
+    pub compare: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BookArgs {
+    #[command(flatten)]
+    pub tapes: TapeArgs,
+
+    /// Only this ticker, all listed tickers by default
+    #[arg(long)]
+    pub ticker: Option<String>,
+
+    /// Price levels shown per side
+    #[arg(long, default_value_t = 5)]
+    pub levels: usize,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct JournalArgs {
+    /// Write-ahead journal, replayed on startup to recover the exchange
+    #[arg(long)]
+    pub journal: Option<PathBuf>,
+
+    /// always, never, or the number of entries between syncs
+    #[arg(long, default_value = "always")]
+    pub fsync: FsyncPolicy,
+
+    /// Where snapshots are written, the newest one is restored on startup
+    #[arg(long, requires = "journal")]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Journal entries between snapshots
+    #[arg(long, default_value_t = 1000)]
+    pub snapshot_every: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RequestsArgs {
+    /// Requests file, stdin when omitted
+    pub input: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub journal: JournalArgs,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServeArgs {
+    #[arg(long, default_value = "127.0.0.1:7000")]
+    pub addr: String,
+
+    /// Where the market data feed is served, no feed when omitted
+    #[arg(long)]
+    pub feed_addr: Option<String>,
+
+    /// Clients tape loaded before accepting sessions
+    #[arg(long)]
+    pub clients: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub journal: JournalArgs,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    #[arg(long, default_value_t = 16)]
+    pub tickers: usize,
+
+    /// Orders per ticker
+    #[arg(long, default_value_t = 20_000)]
+    pub orders: usize,
+
+    /// Thread counts to compare, the first one is the baseline
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 2, 4, 8])]
+    pub threads: Vec<usize>,
+
+    /// Also runs the orders as journaled requests, written to this file
+    #[arg(long)]
+    pub journal: Option<PathBuf>,
+
+    /// Sync policy of the journaled runs
+    #[arg(long, default_value = "always", requires = "journal")]
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for ReplayArgs {
+    fn default() -> Self {
+        ReplayArgs {
+            tapes: TapeArgs {
+                clients: PathBuf::from("./Clients.txt"),
+                orders: PathBuf::from("./Orders.txt"),
+                opening_auction: false,
+            },
+            output: PathBuf::from("result.txt"),
+            format: OutputFormat::Text,
+            compare: None,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Tab separated, one line per client
+    Text,
+    Json,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_replay() {
+        let cli = Cli::parse_from([
+            "engine",
+            "-vv",
+            "replay",
+            "--orders",
+            "tape.txt",
+            "--format",
+            "json",
+            "--compare",
+            "expected.txt",
+        ]);
+        assert_eq!(cli.verbose, 2);
+        match cli.command {
+            Some(Command::Replay(args)) => {
+                assert_eq!(args.tapes.orders, PathBuf::from("tape.txt"));
+                assert_eq!(args.tapes.clients, PathBuf::from("./Clients.txt"));
+                assert_eq!(args.output, PathBuf::from("result.txt"));
+                assert_eq!(args.format, OutputFormat::Json);
+                assert_eq!(args.compare, Some(PathBuf::from("expected.txt")));
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_book_and_default() {
+        let cli = Cli::parse_from(["engine", "book", "--ticker", "A", "--levels", "3"]);
+        match cli.command {
+            Some(Command::Book(args)) => {
+                assert_eq!(args.ticker.as_deref(), Some("A"));
+                assert_eq!(args.levels, 3);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+        assert!(Cli::parse_from(["engine"]).command.is_none());
+        match Cli::parse_from(["engine", "requests", "in.jsonl", "--journal", "j.log"]).command {
+            Some(Command::Requests(args)) => {
+                assert_eq!(args.input, Some(PathBuf::from("in.jsonl")));
+                assert_eq!(args.journal.journal, Some(PathBuf::from("j.log")));
+                assert_eq!(args.journal.fsync, FsyncPolicy::Always);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+        match Cli::parse_from(["engine", "bench", "--threads", "1,3"]).command {
+            Some(Command::Bench(args)) => {
+                assert_eq!(args.threads, vec![1, 3]);
+                assert_eq!(args.tickers, 16);
+                assert_eq!(args.journal, None);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+        match Cli::parse_from(["engine", "bench", "--journal", "j.log", "--fsync", "never"]).command
+        {
+            Some(Command::Bench(args)) => {
+                assert_eq!(args.journal, Some(PathBuf::from("j.log")));
+                assert_eq!(args.fsync, FsyncPolicy::Never);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+}
@@ -1,21 +1,26 @@
-use crate::{ExchangeError, Operation, Result};
+use crate::{ExchangeError, Money, Operation, Price, Result};
+use serde::{Deserialize, Serialize};
 use std::cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd};
 use std::collections::HashMap;
 // use crate::Operation;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Client {
     pub name: String,
-    balance: u32,
+    balance: Money,
+    reserved_balance: Money,
     amount_of_stocks: HashMap<String, u32>,
+    reserved_stocks: HashMap<String, u32>,
 }
 
 impl Client {
-    pub fn new(name: &str, balance: u32) -> Self {
+    pub fn new(name: &str, balance: Money) -> Self {
         Client {
             name: name.to_string(),
             balance,
+            reserved_balance: Money::ZERO,
             amount_of_stocks: HashMap::new(),
+            reserved_stocks: HashMap::new(),
         }
     }
 
@@ -23,72 +28,280 @@ impl Client {
         &self.name
     }
 
-    pub fn get_balance(&self) -> u32 {
+    pub fn get_balance(&self) -> Money {
         self.balance
     }
 
+    /// Money not locked by resting buy orders.
+    pub fn get_available_balance(&self) -> Money {
+        self.balance.saturating_sub(self.reserved_balance)
+    }
+
+    pub fn get_reserved_balance(&self) -> Money {
+        self.reserved_balance
+    }
+
     pub fn get_amount_of_stock(&self, ticker: &str) -> &u32 {
         self.amount_of_stocks.get(ticker).unwrap_or(&0)
     }
 
-    pub fn get_stocks(&self) -> Vec<(&String, &u32)> {
-        Vec::from_iter(self.amount_of_stocks.iter())
+    /// Stocks not locked by resting sell orders.
+    pub fn get_available_stock(&self, ticker: &str) -> u32 {
+        self.get_amount_of_stock(ticker)
+            .saturating_sub(self.get_reserved_stock(ticker))
     }
 
-    pub fn update_stock_balance(
+    pub fn get_reserved_stock(&self, ticker: &str) -> u32 {
+        *self.reserved_stocks.get(ticker).unwrap_or(&0)
+    }
+
+    /// Locks what the order may need: money for a buy, stocks for a sell.
+    pub fn reserve(
         &mut self,
         ticker: &str,
         amount: u32,
         operation: Operation,
+        price: Price,
     ) -> Result<()> {
         match operation {
             Operation::Buy => {
-                *self.amount_of_stocks.entry(ticker.to_string()).or_insert(0) += amount;
+                let cost = price
+                    .checked_mul(amount)
+                    .ok_or(ExchangeError::BuyerDoesntHaveEnoughMoney)?;
+                if self.get_available_balance() < cost {
+                    return Err(ExchangeError::BuyerDoesntHaveEnoughMoney);
+                }
+                self.reserved_balance = self
+                    .reserved_balance
+                    .checked_add(cost)
+                    .ok_or(ExchangeError::AddOverflow)?;
             }
             Operation::Sell => {
-                // if
-                let already_amount = self.amount_of_stocks.get_mut(ticker).unwrap();
-                *already_amount = match already_amount.checked_sub(amount) {
-                    Some(value) => value,
-                    None => {
-                        return Err(ExchangeError::SubtractionOverflow);
-                    }
+                if self.get_available_stock(ticker) < amount {
+                    return Err(ExchangeError::NotEnoughStocks);
                 }
+                *self.reserved_stocks.entry(ticker.to_string()).or_insert(0) += amount;
             }
         }
         Ok(())
     }
 
-    pub fn update_balance(
+    /// Gives back a reservation made by `reserve` with the same arguments.
+    pub fn release(
         &mut self,
         ticker: &str,
         amount: u32,
         operation: Operation,
-        price: u32,
+        price: Price,
     ) -> Result<()> {
-        let cost_of_tx = amount.checked_mul(price).unwrap();
-        if (self.balance < cost_of_tx) && (operation == Operation::Buy) {
-            return Err(ExchangeError::BuyerDoesntHaveEnoughMoney);
-        }
-        match self.update_stock_balance(ticker, amount, operation) {
-            Ok(_) => (),
-            Err(err) => {
-                println!("{:?}", err);
-                return Err(ExchangeError::SubtractionOverflow);
+        match operation {
+            Operation::Buy => {
+                let cost = price
+                    .checked_mul(amount)
+                    .ok_or(ExchangeError::SubtractionOverflow)?;
+                self.reserved_balance = self
+                    .reserved_balance
+                    .checked_sub(cost)
+                    .ok_or(ExchangeError::SubtractionOverflow)?;
             }
+            Operation::Sell => {
+                let reserved = self
+                    .reserved_stocks
+                    .get_mut(ticker)
+                    .ok_or(ExchangeError::SubtractionOverflow)?;
+                *reserved = reserved
+                    .checked_sub(amount)
+                    .ok_or(ExchangeError::SubtractionOverflow)?;
+                if *reserved == 0 {
+                    self.reserved_stocks.remove(ticker);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_stocks(&self) -> Vec<(&String, &u32)> {
+        Vec::from_iter(self.amount_of_stocks.iter())
+    }
+
+    pub fn get_reserved_stocks(&self) -> Vec<(&String, &u32)> {
+        Vec::from_iter(self.reserved_stocks.iter())
+    }
+
+    /// Adds money.
+    pub fn deposit(&mut self, amount: Money) -> Result<()> {
+        self.balance = self
+            .balance
+            .checked_add(amount)
+            .ok_or(ExchangeError::AddOverflow)?;
+        Ok(())
+    }
+
+    /// Adds stocks of `ticker`.
+    pub fn deposit_stock(&mut self, ticker: &str, amount: u32) -> Result<()> {
+        let held = self.amount_of_stocks.entry(ticker.to_string()).or_insert(0);
+        *held = held.checked_add(amount).ok_or(ExchangeError::AddOverflow)?;
+        Ok(())
+    }
+
+    /// Takes out money not reserved by resting orders.
+    pub fn withdraw(&mut self, amount: Money) -> Result<()> {
+        if self.get_available_balance() < amount {
+            return Err(ExchangeError::NotEnoughMoney);
+        }
+        self.balance = self.balance.saturating_sub(amount);
+        Ok(())
+    }
+
+    /// Takes out stocks of `ticker` not reserved by resting orders.
+    pub fn withdraw_stock(&mut self, ticker: &str, amount: u32) -> Result<()> {
+        if self.get_available_stock(ticker) < amount {
+            return Err(ExchangeError::NotEnoughStocks);
+        }
+        if let Some(held) = self.amount_of_stocks.get_mut(ticker) {
+            *held -= amount;
+        }
+        Ok(())
+    }
+
+    /// Adds bought stocks or takes away sold ones. Nothing changes when
+    /// it fails.
+    pub fn update_stock_balance(
+        &mut self,
+        ticker: &str,
+        amount: u32,
+        operation: Operation,
+    ) -> Result<()> {
+        let held = self.stock_after(ticker, amount, operation)?;
+        self.amount_of_stocks.insert(ticker.to_string(), held);
+        Ok(())
+    }
+
+    /// Settles one side of a trade: stocks and money move together, or
+    /// nothing changes when either would overflow or run short.
+    pub fn update_balance(
+        &mut self,
+        ticker: &str,
+        amount: u32,
+        operation: Operation,
+        price: Price,
+    ) -> Result<()> {
+        let cost_of_tx = price
+            .checked_mul(amount)
+            .ok_or(ExchangeError::AddOverflow)?;
+        let balance = match operation {
+            Operation::Buy => self
+                .balance
+                .checked_sub(cost_of_tx)
+                .ok_or(ExchangeError::BuyerDoesntHaveEnoughMoney)?,
+            Operation::Sell => self
+                .balance
+                .checked_add(cost_of_tx)
+                .ok_or(ExchangeError::AddOverflow)?,
+        };
+        let held = self.stock_after(ticker, amount, operation)?;
+        self.amount_of_stocks.insert(ticker.to_string(), held);
+        self.balance = balance;
+        Ok(())
+    }
+
+    /// Settles a trade of `amount` stocks at `price`. Both sides are
+    /// checked before either changes, so a failure leaves both clients as
+    /// they were. The buyer's money was reserved at `buyer_limit`.
+    pub fn settle(
+        buyer: &mut Client,
+        seller: &mut Client,
+        ticker: &str,
+        amount: u32,
+        price: Price,
+        buyer_limit: Price,
+    ) -> Result<()> {
+        let buy = buyer.settlement_leg(ticker, amount, Operation::Buy, price, buyer_limit)?;
+        let sell = seller.settlement_leg(ticker, amount, Operation::Sell, price, price)?;
+        buyer.apply_leg(ticker, buy);
+        seller.apply_leg(ticker, sell);
+        Ok(())
+    }
+
+    /// Balances after one side of a trade, whose reservation was made at
+    /// `reserved_price`.
+    fn settlement_leg(
+        &self,
+        ticker: &str,
+        amount: u32,
+        operation: Operation,
+        price: Price,
+        reserved_price: Price,
+    ) -> Result<Leg> {
+        let cost_of_tx = price
+            .checked_mul(amount)
+            .ok_or(ExchangeError::AddOverflow)?;
+        let mut leg = Leg {
+            balance: self.balance,
+            reserved_balance: self.reserved_balance,
+            stock: self.stock_after(ticker, amount, operation)?,
+            reserved_stock: self.get_reserved_stock(ticker),
         };
         match operation {
             Operation::Buy => {
-                self.balance = self.balance.checked_sub(cost_of_tx).unwrap();
+                leg.reserved_balance = reserved_price
+                    .checked_mul(amount)
+                    .and_then(|reserved| leg.reserved_balance.checked_sub(reserved))
+                    .ok_or(ExchangeError::SubtractionOverflow)?;
+                leg.balance = leg
+                    .balance
+                    .checked_sub(cost_of_tx)
+                    .ok_or(ExchangeError::BuyerDoesntHaveEnoughMoney)?;
             }
             Operation::Sell => {
-                self.balance = self.balance.checked_add(cost_of_tx).unwrap();
+                leg.reserved_stock = leg
+                    .reserved_stock
+                    .checked_sub(amount)
+                    .ok_or(ExchangeError::SubtractionOverflow)?;
+                leg.balance = leg
+                    .balance
+                    .checked_add(cost_of_tx)
+                    .ok_or(ExchangeError::AddOverflow)?;
             }
         }
-        Ok(())
+        Ok(leg)
+    }
+
+    fn apply_leg(&mut self, ticker: &str, leg: Leg) {
+        self.balance = leg.balance;
+        self.reserved_balance = leg.reserved_balance;
+        self.amount_of_stocks.insert(ticker.to_string(), leg.stock);
+        if leg.reserved_stock == 0 {
+            self.reserved_stocks.remove(ticker);
+        } else {
+            self.reserved_stocks
+                .insert(ticker.to_string(), leg.reserved_stock);
+        }
+    }
+
+    /// What the client would hold of `ticker` after buying or selling
+    /// `amount`.
+    fn stock_after(&self, ticker: &str, amount: u32, operation: Operation) -> Result<u32> {
+        let held = *self.get_amount_of_stock(ticker);
+        match operation {
+            Operation::Buy => held.checked_add(amount).ok_or(ExchangeError::AddOverflow),
+            Operation::Sell => held
+                .checked_sub(amount)
+                .ok_or(ExchangeError::NotEnoughStocks),
+        }
     }
 }
 
+/// Balances of one client after its side of a trade, worked out before
+/// anything is written.
+#[derive(Debug, Clone, Copy)]
+struct Leg {
+    balance: Money,
+    reserved_balance: Money,
+    stock: u32,
+    reserved_stock: u32,
+}
+
 impl Ord for Client {
     fn cmp(&self, other: &Self) -> Ordering {
         self.name.cmp(&other.name)
@@ -115,15 +328,15 @@ mod tests {
 
     #[test]
     fn test_creation_and_getters() {
-        let client = Client::new("C", 0);
+        let client = Client::new("C", 0.into());
         assert_eq!(client.get_name(), "C");
-        assert_eq!(client.get_balance(), 0);
+        assert_eq!(client.get_balance(), 0.into());
         assert_eq!(client.get_stocks().len(), 0);
     }
 
     #[test]
     fn test_add_stock() {
-        let mut client = Client::new("C", 0);
+        let mut client = Client::new("C", 0.into());
         let _ = client.update_stock_balance("A", 10, Operation::Buy);
         assert_eq!(client.get_stocks().len(), 1);
         assert_eq!(*client.get_amount_of_stock("A"), 10);
@@ -139,7 +352,7 @@ mod tests {
 
     #[test]
     fn test_sub_stock() {
-        let mut client = Client::new("C", 0);
+        let mut client = Client::new("C", 0.into());
         let _ = client.update_stock_balance("A", 100, Operation::Buy);
         assert_eq!(client.get_stocks().len(), 1);
         assert_eq!(*client.get_amount_of_stock("A"), 100);
@@ -160,23 +373,223 @@ mod tests {
 
     #[tokio::test]
     async fn test_update_balance() {
-        let mut client = Client::new("C", 0);
+        let mut client = Client::new("C", 0.into());
         let _ = client.update_stock_balance("A", 100, Operation::Buy);
         assert_eq!(client.get_stocks().len(), 1);
         assert_eq!(*client.get_amount_of_stock("A"), 100);
-        let _ = client.update_balance("A", 40, Operation::Sell, 10);
+        let _ = client.update_balance("A", 40, Operation::Sell, 10.into());
         assert_eq!(client.get_stocks().len(), 1);
         assert_eq!(*client.get_amount_of_stock("A"), 60);
-        assert_eq!(client.get_balance(), 40 * 10);
+        assert_eq!(client.get_balance(), Price::from(40 * 10));
 
-        let _ = client.update_balance("A", 40, Operation::Sell, 10);
+        let _ = client.update_balance("A", 40, Operation::Sell, 10.into());
         assert_eq!(client.get_stocks().len(), 1);
         assert_eq!(*client.get_amount_of_stock("A"), 20);
-        assert_eq!(client.get_balance(), 40 * 20);
+        assert_eq!(client.get_balance(), Price::from(40 * 20));
 
-        let _ = client.update_balance("A", 40, Operation::Buy, 10);
+        let _ = client.update_balance("A", 40, Operation::Buy, 10.into());
         assert_eq!(client.get_stocks().len(), 1);
         assert_eq!(*client.get_amount_of_stock("A"), 60);
-        assert_eq!(client.get_balance(), 40 * 10);
+        assert_eq!(client.get_balance(), Price::from(40 * 10));
+    }
+
+    #[test]
+    fn test_reserve_and_release_balance() {
+        let mut client = Client::new("C", 100.into());
+        assert_eq!(client.reserve("A", 5, Operation::Buy, 10.into()), Ok(()));
+        assert_eq!(client.get_balance(), 100.into());
+        assert_eq!(client.get_reserved_balance(), 50.into());
+        assert_eq!(client.get_available_balance(), 50.into());
+        assert_eq!(
+            client.reserve("A", 6, Operation::Buy, 10.into()),
+            Err(ExchangeError::BuyerDoesntHaveEnoughMoney)
+        );
+        assert_eq!(client.release("A", 2, Operation::Buy, 10.into()), Ok(()));
+        assert_eq!(client.get_available_balance(), 70.into());
+        assert_eq!(
+            client.release("A", 10, Operation::Buy, 10.into()),
+            Err(ExchangeError::SubtractionOverflow)
+        );
+        assert_eq!(client.get_reserved_balance(), 30.into());
+    }
+
+    #[test]
+    fn test_reserve_and_release_stock() {
+        let mut client = Client::new("C", 0.into());
+        let _ = client.update_stock_balance("A", 10, Operation::Buy);
+        assert_eq!(client.reserve("A", 7, Operation::Sell, 1.into()), Ok(()));
+        assert_eq!(*client.get_amount_of_stock("A"), 10);
+        assert_eq!(client.get_reserved_stock("A"), 7);
+        assert_eq!(client.get_available_stock("A"), 3);
+        assert_eq!(
+            client.reserve("A", 4, Operation::Sell, 1.into()),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        assert_eq!(
+            client.reserve("B", 1, Operation::Sell, 1.into()),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        assert_eq!(client.release("A", 7, Operation::Sell, 1.into()), Ok(()));
+        assert_eq!(client.get_available_stock("A"), 10);
+        assert_eq!(
+            client.release("A", 1, Operation::Sell, 1.into()),
+            Err(ExchangeError::SubtractionOverflow)
+        );
+    }
+
+    #[test]
+    fn test_deposit_and_withdraw() {
+        let mut client = Client::new("C1", 100.into());
+        client.deposit(50.into()).unwrap();
+        client.deposit_stock("A", 10).unwrap();
+        assert_eq!(client.get_balance(), 150.into());
+        assert_eq!(client.get_amount_of_stock("A"), &10);
+
+        client.reserve("A", 4, Operation::Sell, 0.into()).unwrap();
+        client.reserve("A", 10, Operation::Buy, 10.into()).unwrap();
+        assert_eq!(
+            client.withdraw_stock("A", 7),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        assert_eq!(
+            client.withdraw(51.into()),
+            Err(ExchangeError::NotEnoughMoney)
+        );
+        assert_eq!(
+            client.withdraw_stock("B", 1),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        client.withdraw_stock("A", 6).unwrap();
+        client.withdraw(50.into()).unwrap();
+        assert_eq!(client.get_balance(), 100.into());
+        assert_eq!(client.get_amount_of_stock("A"), &4);
+        assert_eq!(
+            client.deposit(Price::from_minor(u64::MAX)),
+            Err(ExchangeError::AddOverflow)
+        );
+    }
+
+    #[test]
+    fn test_stock_errors_leave_client_untouched() {
+        let mut client = Client::new("C", 0.into());
+        assert_eq!(
+            client.update_stock_balance("A", 1, Operation::Sell),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        assert_eq!(client.get_stocks().len(), 0);
+        client
+            .update_stock_balance("A", u32::MAX, Operation::Buy)
+            .unwrap();
+        assert_eq!(
+            client.update_stock_balance("A", 1, Operation::Buy),
+            Err(ExchangeError::AddOverflow)
+        );
+        assert_eq!(*client.get_amount_of_stock("A"), u32::MAX);
+    }
+
+    #[test]
+    fn test_balance_errors_leave_client_untouched() {
+        let huge = Price::from_minor(u64::MAX);
+        let mut client = Client::new("C", 100.into());
+        client
+            .update_stock_balance("A", 10, Operation::Buy)
+            .unwrap();
+
+        // cost does not fit
+        assert_eq!(
+            client.update_balance("A", 2, Operation::Buy, huge),
+            Err(ExchangeError::AddOverflow)
+        );
+        assert_eq!(
+            client.update_balance("A", 11, Operation::Buy, 10.into()),
+            Err(ExchangeError::BuyerDoesntHaveEnoughMoney)
+        );
+        assert_eq!(
+            client.update_balance("A", 11, Operation::Sell, 1.into()),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        assert_eq!(
+            client.update_balance("B", 1, Operation::Sell, 1.into()),
+            Err(ExchangeError::NotEnoughStocks)
+        );
+        assert_eq!(client.get_balance(), 100.into());
+        assert_eq!(*client.get_amount_of_stock("A"), 10);
+
+        // money fits, the stocks bought do not
+        client
+            .update_stock_balance("A", u32::MAX - 10, Operation::Buy)
+            .unwrap();
+        assert_eq!(
+            client.update_balance("A", 1, Operation::Buy, 1.into()),
+            Err(ExchangeError::AddOverflow)
+        );
+        assert_eq!(client.get_balance(), 100.into());
+
+        // the proceeds do not fit
+        let mut client = Client::new("C", huge);
+        client.update_stock_balance("A", 1, Operation::Buy).unwrap();
+        assert_eq!(
+            client.update_balance("A", 1, Operation::Sell, 1.into()),
+            Err(ExchangeError::AddOverflow)
+        );
+        assert_eq!(client.get_balance(), huge);
+        assert_eq!(*client.get_amount_of_stock("A"), 1);
+    }
+
+    #[test]
+    fn test_settle() {
+        let mut buyer = Client::new("B", 100.into());
+        let mut seller = Client::new("S", 0.into());
+        seller
+            .update_stock_balance("A", 10, Operation::Buy)
+            .unwrap();
+        buyer.reserve("A", 5, Operation::Buy, 12.into()).unwrap();
+        seller.reserve("A", 5, Operation::Sell, 10.into()).unwrap();
+
+        Client::settle(&mut buyer, &mut seller, "A", 3, 10.into(), 12.into()).unwrap();
+        assert_eq!(buyer.get_balance(), 70.into());
+        assert_eq!(buyer.get_reserved_balance(), 24.into());
+        assert_eq!(*buyer.get_amount_of_stock("A"), 3);
+        assert_eq!(seller.get_balance(), 30.into());
+        assert_eq!(seller.get_reserved_stock("A"), 2);
+        assert_eq!(*seller.get_amount_of_stock("A"), 7);
+
+        Client::settle(&mut buyer, &mut seller, "A", 2, 10.into(), 12.into()).unwrap();
+        assert_eq!(seller.get_reserved_stocks().len(), 0);
+    }
+
+    #[test]
+    fn test_failed_settlement_changes_neither_side() {
+        let mut buyer = Client::new("B", 100.into());
+        let mut seller = Client::new("S", Price::from_minor(u64::MAX));
+        seller
+            .update_stock_balance("A", 10, Operation::Buy)
+            .unwrap();
+        buyer.reserve("A", 5, Operation::Buy, 10.into()).unwrap();
+        seller.reserve("A", 5, Operation::Sell, 10.into()).unwrap();
+        let (buyer_before, seller_before) = (buyer.clone(), seller.clone());
+
+        // the seller's proceeds overflow after the buyer's side checked out
+        assert_eq!(
+            Client::settle(&mut buyer, &mut seller, "A", 5, 10.into(), 10.into()),
+            Err(ExchangeError::AddOverflow)
+        );
+        // more than the buyer reserved
+        assert_eq!(
+            Client::settle(&mut buyer, &mut seller, "A", 6, 1.into(), 10.into()),
+            Err(ExchangeError::SubtractionOverflow)
+        );
+        for (client, before) in [(&buyer, &buyer_before), (&seller, &seller_before)] {
+            assert_eq!(client.get_balance(), before.get_balance());
+            assert_eq!(client.get_reserved_balance(), before.get_reserved_balance());
+            assert_eq!(
+                client.get_amount_of_stock("A"),
+                before.get_amount_of_stock("A")
+            );
+            assert_eq!(
+                client.get_reserved_stock("A"),
+                before.get_reserved_stock("A")
+            );
+        }
     }
 }
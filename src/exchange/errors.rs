@@ -1,7 +1,9 @@
+use crate::Price;
+use serde::Serialize;
 use thiserror::Error;
 pub type Result<T> = std::result::Result<T, ExchangeError>;
 
-#[derive(Error, Debug, Eq, PartialEq)]
+#[derive(Error, Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum ExchangeError {
     #[error("The provided client with that username already registered")]
     UserAlreadyRegistered,
@@ -29,4 +31,79 @@ pub enum ExchangeError {
 
     #[error("Problem with subtraction overflow")]
     SubtractionOverflow,
+
+    #[error("Buyer and seller are the same client")]
+    SelfTrade,
+
+    #[error("No resting order with that id")]
+    UnknownOrder,
+
+    #[error("Order amount must be greater than zero")]
+    ZeroAmount,
+
+    #[error("Order price must be greater than zero")]
+    ZeroPrice,
+
+    #[error("Ticker is not listed on the exchange")]
+    UnknownTicker,
+
+    #[error("Ticker is already listed on the exchange")]
+    TickerAlreadyListed,
+
+    #[error("Problem with reading or writing file {0}")]
+    ProblemWithFile(String),
+
+    #[error("More amount columns than tickers")]
+    TooManyColumns,
+
+    #[error("The client does not have enough money")]
+    NotEnoughMoney,
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Problem with network connection {0}")]
+    ProblemWithConnection(String),
+
+    #[error("Journal entry after sequence {0} is corrupt")]
+    CorruptJournal(u64),
+
+    #[error("Journal entries after sequence {0} are missing")]
+    JournalGap(u64),
+
+    #[error("Snapshot version {0} is not supported")]
+    UnsupportedSnapshot(u32),
+
+    #[error("Order price is not a multiple of the tick size {0}")]
+    OffTick(Price),
+
+    #[error("Order amount is not a multiple of the lot size {0}")]
+    OddLot(u32),
+
+    #[error("Post-only order would trade on arrival")]
+    WouldTrade,
+
+    #[error("Post-only order must be a limit order that can rest")]
+    PostOnlyCannotRest,
+
+    #[error("Trading in the ticker is halted")]
+    TickerHalted,
+
+    #[error("Trading in the ticker is closed")]
+    TickerClosed,
+
+    #[error("Order price is outside the price band")]
+    OutsidePriceBand,
+
+    #[error("Only orders that can rest are taken during an auction call")]
+    CannotRestInAuction,
+
+    #[error("Ticker is in an auction call, end the auction to leave it")]
+    AuctionInProgress,
+
+    #[error("Ticker is not in an auction call")]
+    NoAuction,
+
+    #[error("A waiting stop order cannot be modified, cancel it and place a new one")]
+    CannotModifyStop,
 }
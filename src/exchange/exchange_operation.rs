@@ -1,604 +1,2974 @@
-use crate::{Client, ExchangeError, Operation, Order, Result};
+use crate::{
+    AuctionPrice, AuctionReport, CircuitBreaker, Client, Depth, ExchangeError, ExecutionReport,
+    Fill, MarketData, MarketEvent, Money, Operation, Order, OrderBook, OrderId, OrderStatus,
+    OrderType, PostOnly, Price, PriceBand, Publisher, Result, SelfTradePrevention, TimeInForce,
+    TradingStatus,
+};
 use std::cmp::min;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use tokio::sync::broadcast;
 
-pub type ClientsDb = Arc<Mutex<HashMap<ClientsName, Client>>>;
-pub type OrdersDb = Arc<Mutex<HashMap<Ticker, HashMap<Operation, HashMap<Price, Vec<Order>>>>>>;
-type Price = u32;
+pub type ClientsDb = Arc<ClientsBase>;
+pub type OrdersDb = Arc<OrdersBase>;
+/// One ticker's book behind its own lock.
+pub type BookDb = Arc<Mutex<OrderBook>>;
 type ClientsName = String;
 type Ticker = String;
 
+/// Books of every listed ticker. Each book has its own lock, so orders for
+/// different tickers match in parallel; the map itself is only written
+/// when a ticker is listed.
+///
+/// Locks are always taken in the same order: one book, then clients (see
+/// `ClientsBase`), then the order index and then the publisher. Code that
+/// needs every book locks them in ticker order.
+#[derive(Debug, Default)]
+pub struct OrdersBase {
+    books: RwLock<HashMap<Ticker, BookDb>>,
+    /// Ticker of every resting and stop order, so an order is found by
+    /// locking only its own book. Kept up to date by `publish`.
+    tickers: RwLock<HashMap<OrderId, Ticker>>,
+    last_order_id: AtomicU64,
+    publisher: Mutex<Publisher>,
+}
+
+impl OrdersBase {
+    fn next_order_id(&self) -> OrderId {
+        self.last_order_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Replaces the books with ones as they were, e.g. from a snapshot.
+    /// New orders get ids after `last_order_id` and market data continues
+    /// after `market_sequence`.
+    pub fn restore(
+        &self,
+        books: HashMap<Ticker, OrderBook>,
+        last_order_id: OrderId,
+        market_sequence: u64,
+    ) {
+        *self.tickers.write().unwrap() = books
+            .iter()
+            .flat_map(|(ticker, book)| {
+                book.orders(Operation::Buy)
+                    .chain(book.orders(Operation::Sell))
+                    .chain(book.get_triggers().orders())
+                    .map(move |order| (order.get_id(), ticker.clone()))
+            })
+            .collect();
+        *self.books.write().unwrap() = books
+            .into_iter()
+            .map(|(ticker, book)| (ticker, Arc::new(Mutex::new(book))))
+            .collect();
+        self.last_order_id.store(last_order_id, Ordering::SeqCst);
+        *self.publisher.lock().unwrap() = Publisher::starting_at(market_sequence);
+    }
+
+    pub fn get_last_order_id(&self) -> OrderId {
+        self.last_order_id.load(Ordering::SeqCst)
+    }
+
+    /// Listed tickers in alphabetical order.
+    pub fn get_tickers(&self) -> Vec<String> {
+        let mut tickers: Vec<String> = self.books.read().unwrap().keys().cloned().collect();
+        tickers.sort_unstable();
+        tickers
+    }
+
+    pub fn get_book(&self, ticker: &str) -> Option<BookDb> {
+        self.books.read().unwrap().get(ticker).cloned()
+    }
+
+    /// Every listed book in ticker order. Code that needs several books at
+    /// once locks them in this order.
+    pub fn get_books(&self) -> Vec<(Ticker, BookDb)> {
+        let mut books: Vec<(Ticker, BookDb)> = self
+            .books
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(ticker, book)| (ticker.clone(), Arc::clone(book)))
+            .collect();
+        books.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        books
+    }
+
+    /// Ticker of a resting order or a stop order waiting for its stop
+    /// price.
+    pub fn get_ticker_of(&self, order_id: OrderId) -> Option<Ticker> {
+        self.tickers.read().unwrap().get(&order_id).cloned()
+    }
+
+    /// A resting order or a stop order waiting for its stop price.
+    pub fn get_order(&self, order_id: OrderId) -> Option<Order> {
+        let book = self.find_book(order_id)?;
+        let book = book.lock().unwrap();
+        book.get(order_id)
+            .or_else(|| book.get_triggers().get(order_id))
+            .cloned()
+    }
+
+    pub fn orders_count(&self) -> usize {
+        self.all_books()
+            .iter()
+            .map(|book| book.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn get_publisher(&self) -> MutexGuard<'_, Publisher> {
+        self.publisher.lock().unwrap()
+    }
+
+    /// Publishes the market data of a change to `book`, with the book
+    /// still locked, and forgets the orders that left it.
+    fn publish(&self, book: &mut OrderBook, events: Vec<MarketEvent>) {
+        let departed = book.take_departed();
+        if !departed.is_empty() {
+            let mut tickers = self.tickers.write().unwrap();
+            for order_id in departed {
+                tickers.remove(&order_id);
+            }
+        }
+        if events.is_empty() {
+            return;
+        }
+        let mut publisher = self.get_publisher();
+        for event in events {
+            publisher.publish(event);
+        }
+    }
+
+    fn all_books(&self) -> Vec<BookDb> {
+        self.books.read().unwrap().values().cloned().collect()
+    }
+
+    /// The book a resting or stop order is on. The order may be gone by
+    /// the time the caller locks the book.
+    fn find_book(&self, order_id: OrderId) -> Option<BookDb> {
+        self.get_book(&self.get_ticker_of(order_id)?)
+    }
+
+    /// Remembers the ticker of an order that stays on its book.
+    fn add_to_index(&self, order_id: OrderId, ticker: Ticker) {
+        self.tickers.write().unwrap().insert(order_id, ticker);
+    }
+}
+
+/// Every client behind its own lock, so settlements of different clients
+/// do not wait for each other. Two clients are only ever locked together
+/// through `with_pair`, which takes them in name order; that is what keeps
+/// two tickers settling for the same clients from deadlocking.
+#[derive(Debug, Default)]
+pub struct ClientsBase {
+    clients: RwLock<HashMap<ClientsName, Arc<Mutex<Client>>>>,
+}
+
+impl ClientsBase {
+    pub fn insert(&self, client: Client) -> Result<()> {
+        let mut clients = self.clients.write().unwrap();
+        if clients.contains_key(client.get_name()) {
+            return Err(ExchangeError::UserAlreadyRegistered);
+        }
+        clients.insert(client.get_name().to_string(), Arc::new(Mutex::new(client)));
+        Ok(())
+    }
+
+    /// A copy of the client's balances and reservations.
+    pub fn get(&self, name: &str) -> Option<Client> {
+        self.entry(name)
+            .map(|client| client.lock().unwrap().clone())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.clients.read().unwrap().contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies of every client, sorted by name.
+    pub fn get_all(&self) -> Vec<Client> {
+        let mut clients: Vec<Client> = self
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .map(|client| client.lock().unwrap().clone())
+            .collect();
+        clients.sort();
+        clients
+    }
+
+    /// Replaces every client, e.g. with ones from a snapshot.
+    pub fn replace(&self, clients: Vec<Client>) {
+        *self.clients.write().unwrap() = clients
+            .into_iter()
+            .map(|client| (client.get_name().to_string(), Arc::new(Mutex::new(client))))
+            .collect();
+    }
+
+    /// Runs `change` on the client with its lock held.
+    pub fn with<T>(&self, name: &str, change: impl FnOnce(&mut Client) -> Result<T>) -> Result<T> {
+        let client = self.entry(name).ok_or(ExchangeError::UnknownUser)?;
+        let mut client = client.lock().unwrap();
+        change(&mut client)
+    }
+
+    /// Runs `change` on two different clients, locked in name order.
+    pub fn with_pair<T>(
+        &self,
+        a: &str,
+        b: &str,
+        change: impl FnOnce(&mut Client, &mut Client) -> Result<T>,
+    ) -> Result<T> {
+        if a == b {
+            return Err(ExchangeError::SelfTrade);
+        }
+        let (first, second) = match (self.entry(a), self.entry(b)) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return Err(ExchangeError::UnknownUser),
+        };
+        if a < b {
+            let mut first = first.lock().unwrap();
+            let mut second = second.lock().unwrap();
+            change(&mut first, &mut second)
+        } else {
+            let mut second = second.lock().unwrap();
+            let mut first = first.lock().unwrap();
+            change(&mut first, &mut second)
+        }
+    }
+
+    fn entry(&self, name: &str) -> Option<Arc<Mutex<Client>>> {
+        self.clients.read().unwrap().get(name).cloned()
+    }
+}
+
 #[derive(Debug)]
 pub struct ExchangeOperation {}
 
 impl ExchangeOperation {
     pub async fn add_client(clients_base: ClientsDb, client: Client) -> Result<()> {
-        let mut clients_base = clients_base.lock().unwrap();
-        if clients_base.contains_key(client.get_name()) {
-            return Err(ExchangeError::UserAlreadyRegistered);
+        clients_base.insert(client)
+    }
+
+    /// A copy of the client's balances and reservations.
+    pub async fn get_client(clients_base: ClientsDb, name: &str) -> Result<Client> {
+        clients_base.get(name).ok_or(ExchangeError::UnknownUser)
+    }
+
+    /// Credits money, or stocks of a listed `ticker`, to a client. Stocks
+    /// only come in whole units.
+    pub async fn deposit(
+        orders_base: OrdersDb,
+        clients_base: ClientsDb,
+        name: &str,
+        ticker: Option<&str>,
+        amount: Money,
+    ) -> Result<()> {
+        Self::check_listed(&orders_base, ticker)?;
+        clients_base.with(name, |client| match ticker {
+            Some(ticker) => client.deposit_stock(ticker, Self::whole_units(amount)?),
+            None => client.deposit(amount),
+        })
+    }
+
+    /// Debits money, or stocks of a listed `ticker`, not reserved by the
+    /// client's resting orders.
+    pub async fn withdraw(
+        orders_base: OrdersDb,
+        clients_base: ClientsDb,
+        name: &str,
+        ticker: Option<&str>,
+        amount: Money,
+    ) -> Result<()> {
+        Self::check_listed(&orders_base, ticker)?;
+        clients_base.with(name, |client| match ticker {
+            Some(ticker) => client.withdraw_stock(ticker, Self::whole_units(amount)?),
+            None => client.withdraw(amount),
+        })
+    }
+
+    /// Trades and book changes of every ticker from now on.
+    pub async fn subscribe(orders_base: OrdersDb) -> broadcast::Receiver<MarketData> {
+        orders_base.get_publisher().subscribe()
+    }
+
+    /// Lists a ticker, orders for unlisted tickers are rejected.
+    pub async fn add_ticker(orders_base: OrdersDb, ticker: &str) -> Result<()> {
+        let mut books = orders_base.books.write().unwrap();
+        if books.contains_key(ticker) {
+            return Err(ExchangeError::TickerAlreadyListed);
         }
-        clients_base.insert(client.get_name().to_string(), client);
+        books.insert(ticker.to_string(), BookDb::default());
         Ok(())
     }
 
-    pub async fn operate(
+    /// Sets the tick size and lot size of a listed ticker. They apply to
+    /// new orders, resting orders stay as they are.
+    pub async fn set_ticker_rules(
+        orders_base: OrdersDb,
+        ticker: &str,
+        tick_size: Price,
+        lot_size: u32,
+    ) -> Result<()> {
+        if tick_size.is_zero() {
+            return Err(ExchangeError::ZeroPrice);
+        }
+        if lot_size == 0 {
+            return Err(ExchangeError::ZeroAmount);
+        }
+        let book = orders_base
+            .get_book(ticker)
+            .ok_or(ExchangeError::UnknownTicker)?;
+        let mut book = book.lock().unwrap();
+        book.set_tick_size(tick_size);
+        book.set_lot_size(lot_size);
+        Ok(())
+    }
+
+    /// Opens, halts or closes a ticker, or starts an auction call. Resting
+    /// and stop orders stay and can be cancelled whatever the status.
+    /// Opening a ticker the circuit breaker halted is how trading resumes.
+    /// A call is only left through `end_auction`, so its crossed book is
+    /// never opened as it is.
+    pub async fn set_trading_status(
+        orders_base: OrdersDb,
+        ticker: &str,
+        status: TradingStatus,
+    ) -> Result<()> {
+        // published under the book lock, so it sits in sequence with the
+        // book's trades
+        Self::write_book(&orders_base, ticker, |book| {
+            if book.get_status() == TradingStatus::Auction && status != TradingStatus::Auction {
+                return Err(ExchangeError::AuctionInProgress);
+            }
+            book.set_status(status);
+            let event = MarketEvent::StatusChanged {
+                ticker: ticker.to_string(),
+                status,
+            };
+            orders_base.publish(book, vec![event]);
+            Ok(())
+        })?
+    }
+
+    /// Ends the auction call of a ticker: every order crossing the auction
+    /// price trades at that price, then the ticker goes to `status`, open
+    /// after an opening auction or closed after a closing one. It is halted
+    /// instead when the auction trades trip the circuit breaker. Once the
+    /// call ends, uncrossing cannot fail half way.
+    pub async fn end_auction(
         orders_base: OrdersDb,
         clients_base: ClientsDb,
-        new_order: Order,
+        ticker: &str,
+        status: TradingStatus,
+    ) -> Result<AuctionReport> {
+        if status == TradingStatus::Auction {
+            return Err(ExchangeError::AuctionInProgress);
+        }
+        let book = orders_base
+            .get_book(ticker)
+            .ok_or(ExchangeError::UnknownTicker)?;
+        let mut book = book.lock().unwrap();
+        if book.get_status() != TradingStatus::Auction {
+            return Err(ExchangeError::NoAuction);
+        }
+        let mut events = Vec::new();
+        let auction = book.auction_price();
+        let fills = match auction {
+            Some(auction) => Self::uncross(&mut book, &clients_base, &mut events, auction),
+            None => Vec::new(),
+        };
+        let status = match book.get_status() {
+            TradingStatus::Halted => TradingStatus::Halted,
+            _ => status,
+        };
+        book.set_status(status);
+        events.push(MarketEvent::StatusChanged {
+            ticker: ticker.to_string(),
+            status,
+        });
+        let triggered = Self::place_triggered(&mut book, &clients_base, &mut events);
+        orders_base.publish(&mut book, events);
+        Ok(AuctionReport {
+            ticker: ticker.to_string(),
+            auction,
+            fills,
+            triggered,
+        })
+    }
+
+    /// Where the ticker's auction would uncross if the call ended now, with
+    /// the volume and imbalance at that price.
+    pub async fn indicative_auction(
+        orders_base: OrdersDb,
+        ticker: &str,
+    ) -> Result<Option<AuctionPrice>> {
+        Self::read_book(orders_base, ticker, OrderBook::auction_price)
+    }
+
+    pub async fn trading_status(orders_base: OrdersDb, ticker: &str) -> Result<TradingStatus> {
+        Self::read_book(orders_base, ticker, OrderBook::get_status)
+    }
+
+    /// Sets the static price band of a ticker, `None` removes it. Resting
+    /// orders outside a new band stay, but do not trade at their price; an
+    /// auction still uncrosses them at a price inside the band.
+    pub async fn set_price_band(
+        orders_base: OrdersDb,
+        ticker: &str,
+        price_band: Option<PriceBand>,
     ) -> Result<()> {
-        let mut orders_base = orders_base.lock().unwrap();
-        let mut clients_base = clients_base.lock().unwrap();
-        if !clients_base.contains_key(new_order.get_client()) {
-            return Err(ExchangeError::UnknownUser);
+        if price_band.is_some_and(|band| band.reference_price.is_zero()) {
+            return Err(ExchangeError::ZeroPrice);
         }
-        if (clients_base
-            .get(new_order.get_client())
-            .unwrap()
-            .get_amount_of_stock(new_order.get_ticker())
-            < &new_order.get_amount())
-            && (new_order.get_operation() == Operation::Sell)
-        {
-            return Ok(());
+        Self::write_book(&orders_base, ticker, |book| book.set_price_band(price_band))
+    }
+
+    /// Sets the circuit breaker of a ticker, `None` removes it.
+    pub async fn set_circuit_breaker(
+        orders_base: OrdersDb,
+        ticker: &str,
+        circuit_breaker: Option<CircuitBreaker>,
+    ) -> Result<()> {
+        Self::write_book(&orders_base, ticker, |book| {
+            book.set_circuit_breaker(circuit_breaker)
+        })
+    }
+
+    /// Matches the order against the book and rests the remainder.
+    /// What the order may need is reserved on the client first, so resting
+    /// orders are always covered. Every order gets an id, invalid orders
+    /// and orders the client cannot cover come back as
+    /// `OrderStatus::Rejected` with the reason. So does a post-only order
+    /// that would trade, unless it asks to be repriced.
+    ///
+    /// A stop order waits off the book, with status `OrderStatus::New`,
+    /// until a trade reaches its stop price. Stop orders activated by the
+    /// trades of this order are placed right after it and reported in
+    /// `ExecutionReport::triggered`.
+    ///
+    /// Only the order's own book is locked, orders for other tickers match
+    /// at the same time.
+    pub async fn operate(
+        orders_base: OrdersDb,
+        clients_base: ClientsDb,
+        mut new_order: Order,
+    ) -> Result<ExecutionReport> {
+        let book = orders_base.get_book(new_order.get_ticker());
+        // ids are taken under the book lock, so they follow the order in
+        // which a book sees its orders
+        let mut book = book.as_ref().map(|book| book.lock().unwrap());
+        let order_id = orders_base.next_order_id();
+        new_order.set_id(order_id);
+        let accepted = Self::validate(book.as_deref(), &clients_base, &new_order)
+            .and_then(|_| Self::post_only(book.as_deref(), &mut new_order))
+            .and_then(|_| Self::reserve(&clients_base, &new_order));
+        let book = match accepted {
+            Ok(()) => book.as_deref_mut().ok_or(ExchangeError::UnknownTicker)?,
+            Err(err) => return Ok(ExecutionReport::rejected(order_id, err)),
+        };
+        let ticker = new_order.get_ticker().to_string();
+        let mut events = Vec::new();
+        let report = Self::enter(book, &clients_base, &mut events, new_order);
+        if book.contains(order_id) || book.get_triggers().contains(order_id) {
+            orders_base.add_to_index(order_id, ticker);
         }
+        orders_base.publish(book, events);
+        report
+    }
+
+    /// Removes a resting or stop order, releases its reservation and
+    /// returns it.
+    pub async fn cancel(
+        orders_base: OrdersDb,
+        clients_base: ClientsDb,
+        order_id: OrderId,
+    ) -> Result<Order> {
+        let book = orders_base
+            .find_book(order_id)
+            .ok_or(ExchangeError::UnknownOrder)?;
+        let mut book = book.lock().unwrap();
+        let mut events = Vec::new();
+        let order = match Self::remove_order(&mut book, &mut events, order_id) {
+            Some(order) => order,
+            None => book
+                .get_triggers_mut()
+                .remove(order_id)
+                .ok_or(ExchangeError::UnknownOrder)?,
+        };
+        orders_base.publish(&mut book, events);
+        Self::release(&clients_base, &order, order.get_amount())?;
+        Ok(order)
+    }
 
-        let mut mut_new_order = Order::new(
-            new_order.get_client(),
-            new_order.get_operation(),
-            new_order.get_ticker(),
-            new_order.get_price(),
-            new_order.get_amount(),
+    /// Changes price and/or amount of a resting order, keeping its id. A
+    /// stop order still waiting for its stop price cannot be modified.
+    /// Reducing the amount at the same price keeps time priority,
+    /// any other change re-enters the order at the back of the queue
+    /// and it may trade immediately, unless it is post-only. A zero amount
+    /// cancels the order.
+    /// If the client cannot cover the new order the old one stays as it was.
+    pub async fn modify(
+        orders_base: OrdersDb,
+        clients_base: ClientsDb,
+        order_id: OrderId,
+        new_price: Price,
+        new_amount: u32,
+    ) -> Result<ExecutionReport> {
+        if new_price.is_zero() {
+            return Err(ExchangeError::ZeroPrice);
+        }
+        let book = orders_base
+            .find_book(order_id)
+            .ok_or(ExchangeError::UnknownOrder)?;
+        let mut book = book.lock().unwrap();
+        let mut events = Vec::new();
+        let report = Self::modify_in(
+            &mut book,
+            &clients_base,
+            &mut events,
+            order_id,
+            new_price,
+            new_amount,
         );
-        let needed_amount = mut_new_order.get_price() * mut_new_order.get_amount();
-        let order_operation = mut_new_order.get_operation();
-        let order_price = mut_new_order.get_price();
+        orders_base.publish(&mut book, events);
+        report
+    }
+
+    fn modify_in(
+        book: &mut OrderBook,
+        clients_base: &ClientsBase,
+        events: &mut Vec<MarketEvent>,
+        order_id: OrderId,
+        new_price: Price,
+        new_amount: u32,
+    ) -> Result<ExecutionReport> {
+        if book.get_triggers().contains(order_id) {
+            return Err(ExchangeError::CannotModifyStop);
+        }
+        let resting = book.get_mut(order_id).ok_or(ExchangeError::UnknownOrder)?;
+        if new_amount == 0 {
+            let order = resting.clone();
+            Self::remove_order(book, events, order_id);
+            Self::release(clients_base, &order, order.get_amount())?;
+            let mut report = ExecutionReport::new(order_id);
+            report.status = OrderStatus::Cancelled;
+            return Ok(report);
+        }
+        if new_price == resting.get_price() && new_amount <= resting.get_amount() {
+            Self::release(clients_base, resting, resting.get_amount() - new_amount)?;
+            resting.set_amount(new_amount);
+            events.push(MarketEvent::order_reduced(resting));
+            let mut report = ExecutionReport::new(order_id);
+            report.status = OrderStatus::Rested;
+            return Ok(report);
+        }
+        let old_order = resting.clone();
+        let mut order = old_order.clone();
+        order.set_price(new_price);
+        order.set_amount(new_amount);
+        Self::check_trading(book, &order)?;
+        Self::check_ticker_rules(book, &order)?;
+        Self::post_only(Some(book), &mut order)?;
+        Self::release(clients_base, &old_order, old_order.get_amount())?;
+        if let Err(err) = Self::reserve(clients_base, &order) {
+            Self::reserve(clients_base, &old_order)?;
+            return Err(err);
+        }
+        Self::remove_order(book, events, order_id);
+        Self::place(book, clients_base, events, order)
+    }
+
+    pub async fn best_bid(orders_base: OrdersDb, ticker: &str) -> Result<Option<Price>> {
+        Self::read_book(orders_base, ticker, OrderBook::best_bid)
+    }
+
+    pub async fn best_ask(orders_base: OrdersDb, ticker: &str) -> Result<Option<Price>> {
+        Self::read_book(orders_base, ticker, OrderBook::best_ask)
+    }
+
+    pub async fn spread(orders_base: OrdersDb, ticker: &str) -> Result<Option<Price>> {
+        Self::read_book(orders_base, ticker, OrderBook::spread)
+    }
+
+    /// Up to `levels` best price levels per side with total amount and
+    /// number of orders at each.
+    pub async fn depth(orders_base: OrdersDb, ticker: &str, levels: usize) -> Result<Depth> {
+        Self::read_book(orders_base, ticker, |book| book.depth(levels))
+    }
+
+    fn read_book<T>(
+        orders_base: OrdersDb,
+        ticker: &str,
+        read: impl FnOnce(&OrderBook) -> T,
+    ) -> Result<T> {
+        let book = orders_base
+            .get_book(ticker)
+            .ok_or(ExchangeError::UnknownTicker)?;
+        let book = book.lock().unwrap();
+        Ok(read(&book))
+    }
+
+    fn write_book<T>(
+        orders_base: &OrdersDb,
+        ticker: &str,
+        write: impl FnOnce(&mut OrderBook) -> T,
+    ) -> Result<T> {
+        let book = orders_base
+            .get_book(ticker)
+            .ok_or(ExchangeError::UnknownTicker)?;
+        let mut book = book.lock().unwrap();
+        Ok(write(&mut book))
+    }
+
+    /// Takes off every `TimeInForce::Day` order, stop orders included,
+    /// releasing reservations, and returns them.
+    pub async fn end_of_day(orders_base: OrdersDb, clients_base: ClientsDb) -> Result<Vec<Order>> {
+        let mut expired = Vec::new();
+        for (_, book) in orders_base.get_books() {
+            let mut book = book.lock().unwrap();
+            let expired_ids: Vec<OrderId> = book
+                .orders(Operation::Buy)
+                .chain(book.orders(Operation::Sell))
+                .filter(|order| order.get_time_in_force() == TimeInForce::Day)
+                .map(Order::get_id)
+                .collect();
+            let expired_stops: Vec<OrderId> = book
+                .get_triggers()
+                .orders()
+                .filter(|order| order.get_time_in_force() == TimeInForce::Day)
+                .map(Order::get_id)
+                .collect();
+            let mut events = Vec::new();
+            for order_id in expired_ids {
+                if let Some(order) = Self::remove_order(&mut book, &mut events, order_id) {
+                    Self::release(&clients_base, &order, order.get_amount())?;
+                    expired.push(order);
+                }
+            }
+            for order_id in expired_stops {
+                if let Some(order) = book.get_triggers_mut().remove(order_id) {
+                    Self::release(&clients_base, &order, order.get_amount())?;
+                    expired.push(order);
+                }
+            }
+            orders_base.publish(&mut book, events);
+        }
+        Ok(expired)
+    }
+
+    fn check_listed(orders_base: &OrdersDb, ticker: Option<&str>) -> Result<()> {
+        match ticker {
+            Some(ticker) if orders_base.get_book(ticker).is_none() => {
+                Err(ExchangeError::UnknownTicker)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate(book: Option<&OrderBook>, clients_base: &ClientsBase, order: &Order) -> Result<()> {
+        if !clients_base.contains(order.get_client()) {
+            return Err(ExchangeError::UnknownUser);
+        }
+        if order.get_amount() == 0 || order.get_display_amount() == Some(0) {
+            return Err(ExchangeError::ZeroAmount);
+        }
+        if order.get_price().is_zero() && order.get_order_type().has_limit() {
+            return Err(ExchangeError::ZeroPrice);
+        }
+        if order.get_order_type().is_stop() && order.get_stop_price().is_none_or(Price::is_zero) {
+            return Err(ExchangeError::ZeroPrice);
+        }
+        let book = book.ok_or(ExchangeError::UnknownTicker)?;
+        Self::check_trading(book, order)?;
+        Self::check_ticker_rules(book, order)
+    }
+
+    /// Rejects orders while the ticker is not open or in an auction call,
+    /// orders that cannot rest during the call and limit prices outside
+    /// its price band.
+    fn check_trading(book: &OrderBook, order: &Order) -> Result<()> {
+        match book.get_status() {
+            TradingStatus::Auction if !order.get_order_type().is_stop() && !order.can_rest() => {
+                return Err(ExchangeError::CannotRestInAuction)
+            }
+            TradingStatus::Open | TradingStatus::Auction => (),
+            TradingStatus::Halted => return Err(ExchangeError::TickerHalted),
+            TradingStatus::Closed => return Err(ExchangeError::TickerClosed),
+        }
+        if order.get_order_type().has_limit() && !book.within_band(order.get_price()) {
+            return Err(ExchangeError::OutsidePriceBand);
+        }
+        Ok(())
+    }
 
-        if (needed_amount
-            > clients_base
-                .get(mut_new_order.get_client())
-                .unwrap()
-                .get_balance())
-            && (mut_new_order.get_operation() == Operation::Buy)
+    /// Rejects limit and stop prices off the ticker's tick size and amounts
+    /// or iceberg slices off its lot size.
+    fn check_ticker_rules(book: &OrderBook, order: &Order) -> Result<()> {
+        let tick_size = book.get_tick_size();
+        let off_tick = |price: Price| !price.is_multiple_of(tick_size);
+        if order.get_order_type().has_limit() && off_tick(order.get_price()) {
+            return Err(ExchangeError::OffTick(tick_size));
+        }
+        if order.get_stop_price().is_some_and(off_tick) {
+            return Err(ExchangeError::OffTick(tick_size));
+        }
+        let lot_size = book.get_lot_size();
+        if !order.get_amount().is_multiple_of(lot_size)
+            || !order
+                .get_display_amount()
+                .unwrap_or(0)
+                .is_multiple_of(lot_size)
         {
+            return Err(ExchangeError::OddLot(lot_size));
+        }
+        Ok(())
+    }
+
+    /// Keeps a post-only order from trading on arrival: it is rejected or
+    /// repriced one tick behind the best opposite price, as it asks.
+    fn post_only(book: Option<&OrderBook>, order: &mut Order) -> Result<()> {
+        if order.get_post_only() == PostOnly::Off {
             return Ok(());
         }
+        if !order.can_rest() {
+            return Err(ExchangeError::PostOnlyCannotRest);
+        }
+        let book = book.ok_or(ExchangeError::UnknownTicker)?;
+        let operation = order.get_operation();
+        let best = match book.best_price(!operation) {
+            Some(best) if Self::is_acceptable(operation, best, order.get_price()) => best,
+            _ => return Ok(()),
+        };
+        if order.get_post_only() == PostOnly::Reject {
+            return Err(ExchangeError::WouldTrade);
+        }
+        let price = match operation {
+            Operation::Buy => best.checked_sub(book.get_tick_size()),
+            Operation::Sell => best.checked_add(book.get_tick_size()),
+        };
+        match price {
+            Some(price) if !price.is_zero() => {
+                order.set_price(price);
+                Ok(())
+            }
+            _ => Err(ExchangeError::WouldTrade),
+        }
+    }
 
-        let mut flag_for_add = true;
-        if let Some(operation_to_price_to_orders) = orders_base.get_mut(mut_new_order.get_ticker())
-        {
-            flag_for_add = false;
-            let mut flag_for_price_to_orders = true;
-            while let (Some(price_to_orders), amount) = (
-                operation_to_price_to_orders.get_mut(&!order_operation),
-                mut_new_order.get_amount(),
-            ) {
-                if amount == 0 {
-                    break;
-                }
-                flag_for_price_to_orders = false;
-                let (price, price_is_acceptable, flag_for_add_update) =
-                    Self::calculate_difference_of_path(
-                        order_operation,
-                        price_to_orders.keys(),
-                        order_price,
-                    );
-                if flag_for_add_update {
-                    flag_for_add = true;
-                    break;
+    fn whole_units(amount: Money) -> Result<u32> {
+        amount.whole_units().ok_or(ExchangeError::ProblemWithNumber)
+    }
+
+    fn reserve(clients_base: &ClientsBase, order: &Order) -> Result<()> {
+        clients_base.with(order.get_client(), |client| {
+            client.reserve(
+                order.get_ticker(),
+                order.get_amount(),
+                order.get_operation(),
+                order.get_price(),
+            )
+        })
+    }
+
+    fn release(clients_base: &ClientsBase, order: &Order, amount: u32) -> Result<()> {
+        Self::release_for(clients_base, order, amount, order.get_price())
+    }
+
+    /// Releases a reservation made at `price` rather than the order's own.
+    fn release_for(
+        clients_base: &ClientsBase,
+        order: &Order,
+        amount: u32,
+        price: Price,
+    ) -> Result<()> {
+        clients_base.with(order.get_client(), |client| {
+            client.release(order.get_ticker(), amount, order.get_operation(), price)
+        })
+    }
+
+    /// Takes a resting order off the book and records the cancel.
+    fn remove_order(
+        book: &mut OrderBook,
+        events: &mut Vec<MarketEvent>,
+        order_id: OrderId,
+    ) -> Option<Order> {
+        let order = book.remove(order_id)?;
+        events.push(MarketEvent::order_cancelled(&order));
+        Self::publish_level_removal(events, book, &order);
+        Some(order)
+    }
+
+    /// Parks a stop order until its stop price is reached, otherwise
+    /// places the order, then places the stop orders its trades activated.
+    fn enter(
+        book: &mut OrderBook,
+        clients_base: &ClientsBase,
+        events: &mut Vec<MarketEvent>,
+        mut new_order: Order,
+    ) -> Result<ExecutionReport> {
+        let reached = book
+            .get_last_price()
+            .is_some_and(|last_price| new_order.is_triggered_by(last_price));
+        let mut report = if new_order.get_order_type().is_stop() && !reached {
+            let report = ExecutionReport::new(new_order.get_id());
+            book.get_triggers_mut().insert(new_order);
+            report
+        } else {
+            new_order.trigger();
+            Self::place(book, clients_base, events, new_order)?
+        };
+        report.triggered = Self::place_triggered(book, clients_base, events);
+        Ok(report)
+    }
+
+    /// Places the stop orders the last trade price has reached, oldest
+    /// first. Stops reached by the trades of an activated order join the
+    /// back of the queue, so a cascade is placed in the order it happened.
+    fn place_triggered(
+        book: &mut OrderBook,
+        clients_base: &ClientsBase,
+        events: &mut Vec<MarketEvent>,
+    ) -> Vec<ExecutionReport> {
+        let mut reports = Vec::new();
+        let mut queue = VecDeque::new();
+        loop {
+            if book.get_status() != TradingStatus::Open {
+                // a halt puts the rest back, the next trade after resuming
+                // activates them again
+                for order in queue {
+                    book.get_triggers_mut().insert(order);
                 }
-                if price_is_acceptable {
-                    let orders = price_to_orders.get_mut(&price).unwrap();
-                    while !orders.is_empty() && (mut_new_order.get_amount() > 0) {
-                        let orders_len = orders.len();
-                        let order = orders.first_mut().unwrap();
-                        let (buyer, seller): (&mut Client, &mut Client) = match order_operation {
-                            Operation::Sell => Self::get_mut_pair(
-                                &mut clients_base,
-                                &order.get_client().to_string(),
-                                &mut_new_order.get_client().to_string(),
-                            ),
-                            Operation::Buy => Self::get_mut_pair(
-                                &mut clients_base,
-                                &mut_new_order.get_client().to_string(),
-                                &order.get_client().to_string(),
-                            ),
-                        };
-
-                        if buyer.get_balance() < order.get_amount() * order.get_price() {
-                            orders.remove(0);
-                            continue;
-                        }
+                return reports;
+            }
+            if let Some(last_price) = book.get_last_price() {
+                queue.extend(book.get_triggers_mut().take_triggered(last_price));
+            }
+            let mut order = match queue.pop_front() {
+                Some(order) => order,
+                None => return reports,
+            };
+            order.trigger();
+            let order_id = order.get_id();
+            let report = Self::place(book, clients_base, events, order)
+                .unwrap_or_else(|err| ExecutionReport::rejected(order_id, err));
+            reports.push(report);
+        }
+    }
 
-                        let amount = min(mut_new_order.get_amount(), order.get_amount());
+    /// Matches `new_order`, already reserved, against `book`. Market data
+    /// goes to `events` in the order it happened.
+    fn place(
+        book: &mut OrderBook,
+        clients_base: &ClientsBase,
+        events: &mut Vec<MarketEvent>,
+        mut new_order: Order,
+    ) -> Result<ExecutionReport> {
+        let ticker = new_order.get_ticker().to_string();
+        let order_operation = new_order.get_operation();
+        let is_market = new_order.get_order_type() == OrderType::Market;
+        let mut report = ExecutionReport::new(new_order.get_id());
+        let mut cancelled = false;
+        // the book halted or the next price is out of band, so whatever is
+        // left does not rest against prices it would cross
+        let mut stopped = false;
 
-                        let buyer_res = buyer.update_balance(
-                            mut_new_order.get_ticker(),
-                            amount,
-                            Operation::Buy,
-                            price,
-                        );
-                        let seller_res = seller.update_balance(
-                            mut_new_order.get_ticker(),
-                            amount,
-                            Operation::Sell,
-                            price,
-                        );
-                        match buyer_res {
-                            Ok(_) => (),
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        };
-                        match seller_res {
-                            Ok(_) => (),
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        };
-                        mut_new_order.sub_amount(amount);
-                        order.sub_amount(amount);
-                        if order.get_amount() == 0 {
-                            orders.remove(0);
-                            assert_eq!(orders.len(), orders_len - 1);
+        if new_order.get_time_in_force() == TimeInForce::Fok
+            && !Self::can_fill(book, clients_base, &new_order)
+        {
+            Self::release(clients_base, &new_order, new_order.get_amount())?;
+            report.status = OrderStatus::Cancelled;
+            return Ok(report);
+        }
+
+        // during an auction call orders only rest
+        while new_order.get_amount() > 0 && book.get_status() != TradingStatus::Auction {
+            let price = match book.best_price(!order_operation) {
+                Some(price) => price,
+                None => break,
+            };
+            if !is_market && !Self::is_acceptable(order_operation, price, new_order.get_price()) {
+                break;
+            }
+            if book.get_status() != TradingStatus::Open || !book.within_band(price) {
+                stopped = true;
+                break;
+            }
+            let order = match book.front_mut(!order_operation) {
+                Some(order) => order,
+                None => break,
+            };
+            // an iceberg trades its visible slice, then refreshes it
+            let mut amount = min(new_order.get_amount(), order.get_visible_amount());
+
+            if order.get_client() == new_order.get_client() {
+                match new_order.get_self_trade_prevention() {
+                    SelfTradePrevention::DecrementAndCancel => {
+                        Self::release(clients_base, order, amount)?;
+                        Self::release(clients_base, &new_order, amount)?;
+                        if order.get_amount() == amount {
+                            events.push(MarketEvent::order_cancelled(order));
+                        }
+                        order.sub_amount(amount)?;
+                        new_order.sub_amount(amount)?;
+                        if order.get_amount() > 0 {
+                            events.push(MarketEvent::order_reduced(order));
                         }
+                        Self::next_slice(book, events, !order_operation);
+                        cancelled = new_order.get_amount() == 0;
+                        continue;
                     }
-                    if orders.is_empty() {
-                        price_to_orders.remove(&price).unwrap();
+                    SelfTradePrevention::CancelOldest => {
+                        Self::cancel_front(book, clients_base, events, !order_operation)?;
+                        continue;
                     }
+                    SelfTradePrevention::CancelBoth => {
+                        Self::cancel_front(book, clients_base, events, !order_operation)?;
+                    }
+                    SelfTradePrevention::CancelNewest => (),
                 }
-                if (!price_is_acceptable) && (mut_new_order.get_amount() > 0) {
-                    if let Some(price_to_orders) =
-                        operation_to_price_to_orders.get_mut(&order_operation)
-                    {
-                        if let Some(orders) = price_to_orders.get_mut(&order_price) {
-                            orders.push(Order::new(
-                                mut_new_order.get_client(),
-                                mut_new_order.get_operation(),
-                                mut_new_order.get_ticker(),
-                                mut_new_order.get_price(),
-                                mut_new_order.get_amount(),
-                            ));
-                        } else {
-                            price_to_orders.insert(
-                                order_price,
-                                vec![Order::new(
-                                    mut_new_order.get_client(),
-                                    mut_new_order.get_operation(),
-                                    mut_new_order.get_ticker(),
-                                    mut_new_order.get_price(),
-                                    mut_new_order.get_amount(),
-                                )],
-                            );
-                        }
-                    } else {
-                        let mut price_to_orders: HashMap<u32, Vec<Order>> = HashMap::new();
-                        price_to_orders.insert(
-                            order_price,
-                            vec![Order::new(
-                                mut_new_order.get_client(),
-                                mut_new_order.get_operation(),
-                                mut_new_order.get_ticker(),
-                                mut_new_order.get_price(),
-                                mut_new_order.get_amount(),
-                            )],
-                        );
-                        operation_to_price_to_orders.insert(order_operation, price_to_orders);
+                Self::release(clients_base, &new_order, new_order.get_amount())?;
+                new_order.set_amount(0);
+                cancelled = true;
+                break;
+            }
+
+            // a market buy reserved nothing up front, it pays for each fill
+            // out of the money still available
+            if is_market && order_operation == Operation::Buy {
+                amount = clients_base.with(new_order.get_client(), |taker| {
+                    let amount = min(amount, taker.get_available_balance().units_at(price));
+                    if amount > 0 {
+                        taker.reserve(&ticker, amount, Operation::Buy, price)?;
                     }
+                    Ok(amount)
+                })?;
+                // out of money, the order stops with the fills it has
+                if amount == 0 {
                     break;
                 }
             }
-            if flag_for_price_to_orders {
-                if let Some(price_to_orders) =
-                    operation_to_price_to_orders.get_mut(&order_operation)
-                {
-                    if let Some(orders) = price_to_orders.get_mut(&order_price) {
-                        orders.push(mut_new_order);
-                    } else {
-                        price_to_orders.insert(new_order.get_price(), vec![mut_new_order]);
+
+            let (buyer, seller, buyer_limit) = match order_operation {
+                Operation::Sell => (order.get_client(), new_order.get_client(), price),
+                Operation::Buy if is_market => (new_order.get_client(), order.get_client(), price),
+                Operation::Buy => (
+                    new_order.get_client(),
+                    order.get_client(),
+                    new_order.get_price(),
+                ),
+            };
+
+            // both sides pay out of what was reserved at their own limit
+            let settled = clients_base.with_pair(buyer, seller, |buyer, seller| {
+                Client::settle(buyer, seller, &ticker, amount, price, buyer_limit)
+            });
+            if settled.is_err() {
+                // the trade did not happen. A maker that cannot settle would
+                // fail every later order the same way, so it leaves the
+                // book; the incoming order keeps its fills and gives back
+                // what it still holds
+                if is_market && order_operation == Operation::Buy {
+                    Self::release_for(clients_base, &new_order, amount, price)?;
+                }
+                let maker_id = order.get_id();
+                if let Some(maker) = Self::remove_order(book, events, maker_id) {
+                    // its reservation may be what failed, so this must not
+                    // fail the incoming order, but it must not go unseen
+                    if let Err(er) = Self::release(clients_base, &maker, maker.get_amount()) {
+                        eprintln!(
+                            "order {} of {} left the book without releasing its reservation: {}",
+                            maker_id,
+                            maker.get_client(),
+                            er
+                        );
                     }
-                } else {
-                    let mut price_to_orders: HashMap<u32, Vec<Order>> = HashMap::new();
-                    price_to_orders.insert(
-                        new_order.get_price(),
-                        vec![Order::new(
-                            mut_new_order.get_client(),
-                            mut_new_order.get_operation(),
-                            mut_new_order.get_ticker(),
-                            mut_new_order.get_price(),
-                            mut_new_order.get_amount(),
-                        )],
-                    );
-                    operation_to_price_to_orders.insert(order_operation, price_to_orders);
                 }
+                Self::release(clients_base, &new_order, new_order.get_amount())?;
+                new_order.set_amount(0);
+                cancelled = true;
+                break;
+            }
+            let fill = Fill {
+                maker: order.get_client().to_string(),
+                taker: new_order.get_client().to_string(),
+                ticker: ticker.clone(),
+                price,
+                amount,
+                maker_order_id: order.get_id(),
+                taker_order_id: new_order.get_id(),
+            };
+            events.push(MarketEvent::trade(&fill, order_operation));
+            report.fills.push(fill);
+            new_order.sub_amount(amount)?;
+            order.sub_amount(amount)?;
+            Self::next_slice(book, events, !order_operation);
+            if book.record_trade(price) {
+                events.push(MarketEvent::StatusChanged {
+                    ticker: ticker.clone(),
+                    status: book.get_status(),
+                });
             }
         }
-        if flag_for_add {
-            let ticker = new_order.get_ticker().to_string();
-            let mut operation_to_price_to_orders: HashMap<Operation, HashMap<u32, Vec<Order>>> =
-                HashMap::new();
-            let mut price_to_orders: HashMap<u32, Vec<Order>> = HashMap::new();
-            price_to_orders.insert(new_order.get_price(), vec![new_order]);
-            operation_to_price_to_orders.insert(order_operation, price_to_orders);
-            orders_base.insert(ticker, operation_to_price_to_orders);
+        report.status = if new_order.get_amount() > 0 && new_order.can_rest() && !stopped {
+            new_order.refresh();
+            events.push(MarketEvent::order_added(&new_order));
+            book.insert(new_order);
+            if report.fills.is_empty() {
+                OrderStatus::Rested
+            } else {
+                OrderStatus::PartiallyFilled
+            }
+        } else if new_order.get_amount() > 0 {
+            Self::release(clients_base, &new_order, new_order.get_amount())?;
+            OrderStatus::Cancelled
+        } else if cancelled {
+            OrderStatus::Cancelled
+        } else {
+            OrderStatus::Filled
+        };
+        Ok(report)
+    }
+
+    /// Trades every order crossing the auction price at that price, best
+    /// price and then oldest first on each side. Each trade is recorded
+    /// like a continuous one, so the circuit breaker may halt the book. A
+    /// pair that cannot settle, e.g. two orders of one client, loses its
+    /// newer order.
+    fn uncross(
+        book: &mut OrderBook,
+        clients_base: &ClientsBase,
+        events: &mut Vec<MarketEvent>,
+        auction: AuctionPrice,
+    ) -> Vec<Fill> {
+        let price = auction.price;
+        let mut fills = Vec::new();
+        loop {
+            let (bid, ask) = match (
+                book.orders(Operation::Buy).next(),
+                book.orders(Operation::Sell).next(),
+            ) {
+                (Some(bid), Some(ask)) if bid.get_price() >= price && ask.get_price() <= price => {
+                    (bid.clone(), ask.clone())
+                }
+                _ => break,
+            };
+            let (maker, taker) = if bid.get_id() < ask.get_id() {
+                (&bid, &ask)
+            } else {
+                (&ask, &bid)
+            };
+            let amount = min(bid.get_visible_amount(), ask.get_visible_amount());
+            let settled =
+                clients_base.with_pair(bid.get_client(), ask.get_client(), |buyer, seller| {
+                    Client::settle(
+                        buyer,
+                        seller,
+                        bid.get_ticker(),
+                        amount,
+                        price,
+                        bid.get_price(),
+                    )
+                });
+            if settled.is_err() {
+                if let Some(order) = Self::remove_order(book, events, taker.get_id()) {
+                    // the trades before stand, so this cannot fail the
+                    // auction, but it must not go unseen
+                    if let Err(er) = Self::release(clients_base, &order, order.get_amount()) {
+                        eprintln!(
+                            "order {} of {} left the book without releasing its reservation: {}",
+                            order.get_id(),
+                            order.get_client(),
+                            er
+                        );
+                    }
+                }
+                continue;
+            }
+            let fill = Fill {
+                maker: maker.get_client().to_string(),
+                taker: taker.get_client().to_string(),
+                ticker: bid.get_ticker().to_string(),
+                price,
+                amount,
+                maker_order_id: maker.get_id(),
+                taker_order_id: taker.get_id(),
+            };
+            events.push(MarketEvent::trade(&fill, taker.get_operation()));
+            fills.push(fill);
+            for order_id in [bid.get_id(), ask.get_id()] {
+                if let Some(order) = book.get_mut(order_id) {
+                    // never more than either order shows, so it cannot fail
+                    let _ = order.sub_amount(amount);
+                }
+            }
+            Self::next_slice(book, events, Operation::Buy);
+            Self::next_slice(book, events, Operation::Sell);
+            // the halt is reported once the book is uncrossed
+            book.record_trade(price);
         }
-        Ok(())
+        fills
+    }
+
+    /// Takes the front order of `operation` off the book once it has fully
+    /// traded. An iceberg whose slice is gone shows the next one out of its
+    /// reserve at the back of the level, losing time priority.
+    fn next_slice(book: &mut OrderBook, events: &mut Vec<MarketEvent>, operation: Operation) {
+        let order = match book.front_mut(operation) {
+            Some(order) => order,
+            None => return,
+        };
+        if order.get_amount() == 0 {
+            if let Some(order) = book.pop_front(operation) {
+                Self::publish_level_removal(events, book, &order);
+            }
+        } else if order.get_visible_amount() == 0 {
+            order.refresh();
+            events.push(MarketEvent::order_added(order));
+            book.requeue_front(operation);
+        }
+    }
+
+    /// Whether the book can fill the whole order right now, walking the
+    /// opposite side the way the matching loop would.
+    fn can_fill(book: &OrderBook, clients_base: &ClientsBase, new_order: &Order) -> bool {
+        let order_operation = new_order.get_operation();
+        let is_market = new_order.get_order_type() == OrderType::Market;
+        let mut funds = match clients_base.get(new_order.get_client()) {
+            Some(client) if is_market && order_operation == Operation::Buy => {
+                Some(client.get_available_balance())
+            }
+            Some(_) => None,
+            None => return false,
+        };
+        let mut needed = new_order.get_amount();
+        let mut breaker = book.get_circuit_breaker().cloned();
+        for order in book.orders(!order_operation) {
+            let price = order.get_price();
+            if !is_market && !Self::is_acceptable(order_operation, price, new_order.get_price()) {
+                return false;
+            }
+            if !book.within_band(price) {
+                return false;
+            }
+            if order.get_client() == new_order.get_client() {
+                if new_order.get_self_trade_prevention() == SelfTradePrevention::CancelOldest {
+                    continue;
+                }
+                return false;
+            }
+            let mut amount = min(needed, order.get_amount());
+            if let Some(funds) = funds.as_mut() {
+                amount = min(amount, funds.units_at(price));
+                let cost = price.checked_mul(amount).unwrap_or(*funds);
+                *funds = funds.saturating_sub(cost);
+            }
+            needed -= amount;
+            if needed == 0 {
+                return true;
+            }
+            // a trade that trips the breaker halts the book before the rest
+            if breaker
+                .as_mut()
+                .is_some_and(|breaker| breaker.record(price))
+            {
+                return false;
+            }
+            if amount < order.get_amount() {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn cancel_front(
+        book: &mut OrderBook,
+        clients_base: &ClientsBase,
+        events: &mut Vec<MarketEvent>,
+        operation: Operation,
+    ) -> Result<()> {
+        match book.pop_front(operation) {
+            Some(order) => {
+                events.push(MarketEvent::order_cancelled(&order));
+                Self::publish_level_removal(events, book, &order);
+                Self::release(clients_base, &order, order.get_amount())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Records `LevelRemoved` when `order` was the last one at its price.
+    fn publish_level_removal(events: &mut Vec<MarketEvent>, book: &OrderBook, order: &Order) {
+        if !book.has_level(order.get_operation(), order.get_price()) {
+            events.push(MarketEvent::LevelRemoved {
+                ticker: order.get_ticker().to_string(),
+                side: order.get_operation(),
+                price: order.get_price(),
+            });
+        }
+    }
+
+    /// Whether a resting order at `price` satisfies the limit of an
+    /// incoming order with the given operation.
+    fn is_acceptable(order_operation: Operation, price: Price, order_price: Price) -> bool {
+        match order_operation {
+            Operation::Buy => price <= order_price,
+            Operation::Sell => price >= order_price,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_addition_of_clients() {
+        let clients_db = ClientsDb::default();
+        let client1 = Client::new("A", 5.into());
+        let client2 = Client::new("B", 4.into());
+        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding {}", er);
+            }
+        }
+        assert_eq!(clients_db.len(), 1);
+        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding {}", er);
+            }
+        }
+        assert_eq!(clients_db.len(), 2);
+
+        if ExchangeOperation::add_client(clients_db.clone(), client2.clone())
+            .await
+            .is_ok()
+        {
+            println!("there should be error");
+        }
+
+        assert_eq!(clients_db.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let orders_db = OrdersDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let clients_db = ClientsDb::default();
+        let mut client1 = Client::new("A", 1000.into());
+        let mut client2 = Client::new("B", 1000.into());
+        let _ = client2.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
+        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding client {}", er);
+            }
+        }
+        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding client {}", er);
+            }
+        }
+        let order1 = Order::new("B", Operation::Buy, "C1", 10.into(), 10);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order1.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order1 {}", er);
+            }
+        }
+        // println!("{:?}", exchange.get_open_orders());
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(clients_db.len(), 2);
+
+        let order2 = Order::new("A", Operation::Sell, "C1", 10.into(), 10);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order2.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order2 {}", er);
+            }
+        }
+        assert_eq!(orders_db.orders_count(), 0);
+        assert_eq!(clients_db.len(), 2);
+
+        let client1_new = clients_db.get("A").unwrap();
+
+        assert_eq!(client1_new.get_balance(), 1100.into());
+        assert_eq!(client1_new.get_amount_of_stock("C1"), &0);
+
+        let client2_new = clients_db.get("B").unwrap();
+
+        assert_eq!(client2_new.get_balance(), 900.into());
+        assert_eq!(client2_new.get_amount_of_stock("C1"), &20);
+    }
+
+    #[tokio::test]
+    async fn test_no_exchange() {
+        let orders_db = OrdersDb::default();
+        for ticker in ["C1", "C2"] {
+            let _ = ExchangeOperation::add_ticker(orders_db.clone(), ticker).await;
+        }
+        let clients_db = ClientsDb::default();
+        let mut client1 = Client::new("A", 1000.into());
+        let mut client2 = Client::new("B", 1000.into());
+        let _ = client2.update_stock_balance("C2", 10, Operation::Buy);
+        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
+        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding client {}", er);
+            }
+        }
+        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding client {}", er);
+            }
+        }
+        let order1 = Order::new("B", Operation::Buy, "C2", 10.into(), 10);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order1.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order1 {}", er);
+            }
+        }
+        // println!("{:?}", exchange.get_open_orders());
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(clients_db.len(), 2);
+
+        let order2 = Order::new("A", Operation::Sell, "C1", 10.into(), 10);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order2.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order2 {}", er);
+            }
+        }
+        println!("{:?}", orders_db);
+        assert_eq!(orders_db.orders_count(), 2);
+        assert_eq!(clients_db.len(), 2);
+
+        let order3 = Order::new("B", Operation::Sell, "C2", 11.into(), 9);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order3.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order3 {}", er);
+            }
+        }
+        assert_eq!(orders_db.orders_count(), 3);
+        assert_eq!(clients_db.len(), 2);
+
+        let order4 = Order::new("A", Operation::Buy, "C1", 9.into(), 11);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order4.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order4 {}", er);
+            }
+        }
+        assert_eq!(orders_db.orders_count(), 4);
+        assert_eq!(clients_db.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_buy1() {
+        let orders_db = OrdersDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let clients_db = ClientsDb::default();
+        let client1 = Client::new("A", 10000.into());
+        let mut client2 = Client::new("B", 10000.into());
+        let _ = client2.update_stock_balance("C1", 100, Operation::Buy);
+        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding client {}", er);
+            }
+        }
+        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with adding client {}", er);
+            }
+        }
+
+        let order1 = Order::new("B", Operation::Sell, "C1", 10.into(), 50);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order1.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order1 {}", er);
+            }
+        }
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(clients_db.len(), 2);
+
+        let order2 = Order::new("B", Operation::Sell, "C1", 15.into(), 50);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order2.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order2 {}", er);
+            }
+        }
+        assert_eq!(orders_db.orders_count(), 2);
+        assert_eq!(clients_db.len(), 2);
+
+        let order3 = Order::new("A", Operation::Buy, "C1", 20.into(), 75);
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order3.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(er) => {
+                println!("error with operating order3 {}", er);
+            }
+        }
+
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(
+            client_a.get_balance(),
+            Price::from(10000 - (10 * 50 + 15 * 25))
+        );
+
+        let client_b = clients_db.get("B").unwrap();
+        assert_eq!(
+            client_b.get_balance(),
+            Price::from(10000 + (10 * 50 + 15 * 25))
+        );
+
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(clients_db.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_order_ids_and_cancel() {
+        let orders_db = OrdersDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let clients_db = ClientsDb::default();
+        let mut client = Client::new("A", 1000.into());
+        let _ = client.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client).await;
+
+        let buy = Order::new("A", Operation::Buy, "C1", 5.into(), 10);
+        let first = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), buy.clone())
+            .await
+            .unwrap()
+            .order_id;
+        let second = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), buy)
+            .await
+            .unwrap()
+            .order_id;
+        assert!(second > first);
+        assert_eq!(orders_db.orders_count(), 2);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            100.into()
+        );
+
+        let cancelled = ExchangeOperation::cancel(orders_db.clone(), clients_db.clone(), first)
+            .await
+            .unwrap();
+        assert_eq!(cancelled.get_id(), first);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            50.into()
+        );
+        assert_eq!(orders_db.orders_count(), 1);
+        assert!(orders_db.get_order(second).is_some());
+        assert_eq!(
+            ExchangeOperation::cancel(orders_db.clone(), clients_db.clone(), first).await,
+            Err(ExchangeError::UnknownOrder)
+        );
+        assert_eq!(
+            ExchangeOperation::operate(
+                orders_db.clone(),
+                clients_db.clone(),
+                Order::new("Z", Operation::Buy, "C1", 5.into(), 10)
+            )
+            .await
+            .unwrap()
+            .status,
+            OrderStatus::Rejected(ExchangeError::UnknownUser)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify() {
+        let orders_db = OrdersDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let clients_db = ClientsDb::default();
+        let mut client1 = Client::new("A", 1000.into());
+        let client2 = Client::new("B", 1000.into());
+        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client1).await;
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client2).await;
+
+        let sell = Order::new("A", Operation::Sell, "C1", 20.into(), 10);
+        let order_id = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), sell)
+            .await
+            .unwrap()
+            .order_id;
+
+        ExchangeOperation::modify(
+            orders_db.clone(),
+            clients_db.clone(),
+            order_id,
+            20.into(),
+            8,
+        )
+        .await
+        .unwrap();
+        assert_eq!(orders_db.get_order(order_id).unwrap().get_amount(), 8);
+
+        let buy = Order::new("B", Operation::Buy, "C1", 10.into(), 5);
+        let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), buy).await;
+        assert_eq!(orders_db.orders_count(), 2);
+
+        ExchangeOperation::modify(
+            orders_db.clone(),
+            clients_db.clone(),
+            order_id,
+            10.into(),
+            8,
+        )
+        .await
+        .unwrap();
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(orders_db.get_order(order_id).unwrap().get_amount(), 3);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_balance(),
+            Price::from(1000 + 5 * 10)
+        );
+        assert_eq!(clients_db.get("B").unwrap().get_amount_of_stock("C1"), &5);
+
+        ExchangeOperation::modify(
+            orders_db.clone(),
+            clients_db.clone(),
+            order_id,
+            10.into(),
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(orders_db.orders_count(), 0);
+        assert_eq!(
+            ExchangeOperation::modify(
+                orders_db.clone(),
+                clients_db.clone(),
+                order_id,
+                10.into(),
+                1
+            )
+            .await,
+            Err(ExchangeError::UnknownOrder)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reservations() {
+        let orders_db = OrdersDb::default();
+        for ticker in ["C1", "C2"] {
+            let _ = ExchangeOperation::add_ticker(orders_db.clone(), ticker).await;
+        }
+        let clients_db = ClientsDb::default();
+        let mut client1 = Client::new("A", 100.into());
+        let mut client2 = Client::new("B", 0.into());
+        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = client2.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client1).await;
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client2).await;
+
+        // resting buy locks the money, the second one cannot reuse it
+        let buy = Order::new("A", Operation::Buy, "C1", 10.into(), 8);
+        let buy_id = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), buy)
+            .await
+            .unwrap()
+            .order_id;
+        assert_eq!(
+            ExchangeOperation::operate(
+                orders_db.clone(),
+                clients_db.clone(),
+                Order::new("A", Operation::Buy, "C2", 10.into(), 3)
+            )
+            .await
+            .unwrap()
+            .status,
+            OrderStatus::Rejected(ExchangeError::BuyerDoesntHaveEnoughMoney)
+        );
+
+        // resting sell locks the stocks
+        let sell = Order::new("A", Operation::Sell, "C1", 50.into(), 6);
+        let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), sell).await;
+        assert_eq!(
+            ExchangeOperation::operate(
+                orders_db.clone(),
+                clients_db.clone(),
+                Order::new("A", Operation::Sell, "C1", 50.into(), 5)
+            )
+            .await
+            .unwrap()
+            .status,
+            OrderStatus::Rejected(ExchangeError::NotEnoughStocks)
+        );
+
+        // taker sells into the resting buy below its limit price
+        let sell = Order::new("B", Operation::Sell, "C1", 9.into(), 5);
+        let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), sell).await;
+        {
+            let client_a = clients_db.get("A").unwrap();
+            assert_eq!(client_a.get_balance(), 50.into());
+            assert_eq!(client_a.get_reserved_balance(), 30.into());
+            assert_eq!(client_a.get_amount_of_stock("C1"), &15);
+            assert_eq!(client_a.get_reserved_stock("C1"), 6);
+            let client_b = clients_db.get("B").unwrap();
+            assert_eq!(client_b.get_balance(), 50.into());
+            assert_eq!(client_b.get_reserved_stock("C1"), 0);
+        }
+
+        // shrinking and cancelling give the reservation back
+        ExchangeOperation::modify(orders_db.clone(), clients_db.clone(), buy_id, 10.into(), 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            10.into()
+        );
+        assert_eq!(
+            ExchangeOperation::modify(orders_db.clone(), clients_db.clone(), buy_id, 10.into(), 6)
+                .await,
+            Err(ExchangeError::BuyerDoesntHaveEnoughMoney)
+        );
+        assert_eq!(orders_db.get_order(buy_id).unwrap().get_amount(), 1);
+        let _ = ExchangeOperation::cancel(orders_db.clone(), clients_db.clone(), buy_id).await;
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
+        );
+    }
+
+    async fn self_trade_setup(prevention: SelfTradePrevention) -> (OrdersDb, ClientsDb) {
+        let orders_db = OrdersDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let clients_db = ClientsDb::default();
+        let mut client1 = Client::new("A", 1000.into());
+        let mut client2 = Client::new("B", 0.into());
+        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = client2.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client1).await;
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client2).await;
+        for order in [
+            Order::new("A", Operation::Sell, "C1", 10.into(), 5),
+            Order::new("B", Operation::Sell, "C1", 11.into(), 5),
+        ] {
+            let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order).await;
+        }
+        let mut buy = Order::new("A", Operation::Buy, "C1", 11.into(), 8);
+        buy.set_self_trade_prevention(prevention);
+        let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), buy).await;
+        (orders_db, clients_db)
+    }
+
+    #[tokio::test]
+    async fn test_self_trade_cancel_newest() {
+        let (orders_db, clients_db) = self_trade_setup(SelfTradePrevention::CancelNewest).await;
+        assert_eq!(orders_db.orders_count(), 2);
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(client_a.get_balance(), 1000.into());
+        assert_eq!(client_a.get_reserved_balance(), 0.into());
+        assert_eq!(client_a.get_reserved_stock("C1"), 5);
+    }
+
+    #[tokio::test]
+    async fn test_self_trade_cancel_oldest() {
+        let (orders_db, clients_db) = self_trade_setup(SelfTradePrevention::CancelOldest).await;
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(
+            orders_db.get_book("C1").unwrap().lock().unwrap().best_bid(),
+            Some(11.into())
+        );
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(client_a.get_balance(), Price::from(1000 - 5 * 11));
+        assert_eq!(client_a.get_reserved_balance(), Price::from(3 * 11));
+        assert_eq!(client_a.get_amount_of_stock("C1"), &15);
+        assert_eq!(client_a.get_reserved_stock("C1"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_self_trade_cancel_both() {
+        let (orders_db, clients_db) = self_trade_setup(SelfTradePrevention::CancelBoth).await;
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(
+            orders_db.get_book("C1").unwrap().lock().unwrap().best_ask(),
+            Some(11.into())
+        );
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(client_a.get_balance(), 1000.into());
+        assert_eq!(client_a.get_reserved_balance(), 0.into());
+        assert_eq!(client_a.get_reserved_stock("C1"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_self_trade_decrement_and_cancel() {
+        let (orders_db, clients_db) =
+            self_trade_setup(SelfTradePrevention::DecrementAndCancel).await;
+        assert_eq!(orders_db.orders_count(), 1);
+        let book = orders_db.get_book("C1").unwrap();
+        let book = book.lock().unwrap();
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(
+            book.orders(Operation::Sell)
+                .map(Order::get_amount)
+                .collect::<Vec<u32>>(),
+            vec![2]
+        );
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(client_a.get_balance(), Price::from(1000 - 3 * 11));
+        assert_eq!(client_a.get_reserved_balance(), 0.into());
+        assert_eq!(client_a.get_amount_of_stock("C1"), &13);
+        assert_eq!(client_a.get_reserved_stock("C1"), 0);
+    }
+
+    #[test]
+    fn test_with_pair_rejects_same_client() {
+        let clients = ClientsBase::default();
+        clients.insert(Client::new("A", 0.into())).unwrap();
+        clients.insert(Client::new("B", 0.into())).unwrap();
+        let names = |a: &mut Client, b: &mut Client| {
+            Ok((a.get_name().to_string(), b.get_name().to_string()))
+        };
+        assert_eq!(
+            clients.with_pair("B", "A", names),
+            Ok(("B".to_string(), "A".to_string()))
+        );
+        assert_eq!(
+            clients.with_pair("A", "A", names),
+            Err(ExchangeError::SelfTrade)
+        );
+        assert_eq!(
+            clients.with_pair("A", "Z", names),
+            Err(ExchangeError::UnknownUser)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execution_reports() {
+        let orders_db = OrdersDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let clients_db = ClientsDb::default();
+        let client1 = Client::new("A", 10000.into());
+        let mut client2 = Client::new("B", 0.into());
+        let _ = client2.update_stock_balance("C1", 100, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client1).await;
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client2).await;
+
+        let sell1 = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("B", Operation::Sell, "C1", 10.into(), 50),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sell1.status, OrderStatus::Rested);
+        assert!(sell1.fills.is_empty());
+        let sell2 = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("B", Operation::Sell, "C1", 15.into(), 50),
+        )
+        .await
+        .unwrap();
+
+        let buy = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", 20.into(), 75),
+        )
+        .await
+        .unwrap();
+        assert_eq!(buy.status, OrderStatus::Filled);
+        assert_eq!(buy.filled_amount(), 75);
+        assert_eq!(
+            buy.fills[0],
+            Fill {
+                maker: "B".to_string(),
+                taker: "A".to_string(),
+                ticker: "C1".to_string(),
+                price: 10.into(),
+                amount: 50,
+                maker_order_id: sell1.order_id,
+                taker_order_id: buy.order_id,
+            }
+        );
+        assert_eq!(buy.fills[1].price, 15.into());
+        assert_eq!(buy.fills[1].amount, 25);
+        assert_eq!(buy.fills[1].maker_order_id, sell2.order_id);
+
+        let buy = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", 15.into(), 30),
+        )
+        .await
+        .unwrap();
+        assert_eq!(buy.status, OrderStatus::PartiallyFilled);
+        assert_eq!(buy.filled_amount(), 25);
+        assert_eq!(orders_db.get_order(buy.order_id).unwrap().get_amount(), 5);
+
+        let rejected = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("B", Operation::Sell, "C1", 15.into(), 1000),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rejected.status,
+            OrderStatus::Rejected(ExchangeError::NotEnoughStocks)
+        );
+        assert!(rejected.order_id > buy.order_id);
+
+        let self_trade = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Sell, "C1", 15.into(), 1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(self_trade.status, OrderStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_rejection_reasons() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        assert_eq!(
+            ExchangeOperation::add_ticker(orders_db.clone(), "C1").await,
+            Ok(())
+        );
+        assert_eq!(
+            ExchangeOperation::add_ticker(orders_db.clone(), "C1").await,
+            Err(ExchangeError::TickerAlreadyListed)
+        );
+        let mut client = Client::new("A", 100.into());
+        let _ = client.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client).await;
+
+        for (order, reason) in [
+            (
+                Order::new("Z", Operation::Buy, "C1", 1.into(), 1),
+                ExchangeError::UnknownUser,
+            ),
+            (
+                Order::new("A", Operation::Buy, "C1", 1.into(), 0),
+                ExchangeError::ZeroAmount,
+            ),
+            (
+                Order::new("A", Operation::Buy, "C1", 0.into(), 1),
+                ExchangeError::ZeroPrice,
+            ),
+            (
+                Order::new("A", Operation::Buy, "C9", 1.into(), 1),
+                ExchangeError::UnknownTicker,
+            ),
+            (
+                Order::new("A", Operation::Buy, "C1", 11.into(), 10),
+                ExchangeError::BuyerDoesntHaveEnoughMoney,
+            ),
+            (
+                Order::new("A", Operation::Sell, "C1", 1.into(), 11),
+                ExchangeError::NotEnoughStocks,
+            ),
+        ] {
+            let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+                .await
+                .unwrap();
+            assert_eq!(report.status, OrderStatus::Rejected(reason));
+        }
+        assert_eq!(orders_db.orders_count(), 0);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
+        );
+        assert_eq!(clients_db.get("A").unwrap().get_reserved_stock("C1"), 0);
+    }
+
+    async fn asks_setup(balance: u32) -> (OrdersDb, ClientsDb) {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let mut client1 = Client::new("A", Price::from(balance));
+        let mut client2 = Client::new("B", 0.into());
+        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = client2.update_stock_balance("C1", 10, Operation::Buy);
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client1).await;
+        let _ = ExchangeOperation::add_client(clients_db.clone(), client2).await;
+        for order in [
+            Order::new("B", Operation::Sell, "C1", 10.into(), 5),
+            Order::new("B", Operation::Sell, "C1", 12.into(), 5),
+        ] {
+            let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order).await;
+        }
+        (orders_db, clients_db)
+    }
+
+    #[tokio::test]
+    async fn test_market_orders() {
+        let (orders_db, clients_db) = asks_setup(74).await;
+        let report = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new_market("A", Operation::Buy, "C1", 20),
+        )
+        .await
+        .unwrap();
+        // 5 at 10 and what is left of the money buys 2 at 12
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(report.filled_amount(), 7);
+        assert_eq!(orders_db.orders_count(), 1);
+        {
+            let client_a = clients_db.get("A").unwrap();
+            assert_eq!(client_a.get_balance(), Price::from(74 - 50 - 24));
+            assert_eq!(client_a.get_reserved_balance(), 0.into());
+            assert_eq!(client_a.get_amount_of_stock("C1"), &17);
+        }
+
+        let _ = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("B", Operation::Buy, "C1", 3.into(), 5),
+        )
+        .await;
+        let report = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new_market("A", Operation::Sell, "C1", 6),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(report.filled_amount(), 5);
+        assert_eq!(clients_db.get("A").unwrap().get_reserved_stock("C1"), 0);
+        assert_eq!(clients_db.get("A").unwrap().get_amount_of_stock("C1"), &12);
+    }
+
+    #[tokio::test]
+    async fn test_immediate_or_cancel() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let mut order = Order::new("A", Operation::Buy, "C1", 11.into(), 8);
+        order.set_time_in_force(TimeInForce::Ioc);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(report.filled_amount(), 5);
+        assert_eq!(orders_db.orders_count(), 1);
+        assert_eq!(clients_db.get("A").unwrap().get_balance(), 950.into());
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fill_or_kill() {
+        let (orders_db, clients_db) = asks_setup(100).await;
+        let mut order = Order::new("A", Operation::Buy, "C1", 11.into(), 9);
+        order.set_time_in_force(TimeInForce::Fok);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert!(report.fills.is_empty());
+        assert_eq!(orders_db.orders_count(), 2);
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(client_a.get_balance(), 100.into());
+        assert_eq!(client_a.get_reserved_balance(), 0.into());
+
+        // the book has 10 but the money covers only 9 of them
+        let mut order = Order::new_market("A", Operation::Buy, "C1", 10);
+        order.set_time_in_force(TimeInForce::Fok);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(orders_db.orders_count(), 2);
+
+        let mut order = Order::new("A", Operation::Buy, "C1", 12.into(), 8);
+        order.set_time_in_force(TimeInForce::Fok);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Filled);
+        assert_eq!(report.filled_amount(), 8);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_balance(),
+            Price::from(100 - 50 - 36)
+        );
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_day_orders_expire() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let mut order = Order::new("A", Operation::Buy, "C1", 5.into(), 10);
+        order.set_time_in_force(TimeInForce::Day);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            50.into()
+        );
+
+        let expired = ExchangeOperation::end_of_day(orders_db.clone(), clients_db.clone())
+            .await
+            .unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].get_id(), report.order_id);
+        assert_eq!(orders_db.orders_count(), 2);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_top_of_book_queries() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let _ = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", 7.into(), 3),
+        )
+        .await;
+        assert_eq!(
+            ExchangeOperation::best_bid(orders_db.clone(), "C1").await,
+            Ok(Some(7.into()))
+        );
+        assert_eq!(
+            ExchangeOperation::best_ask(orders_db.clone(), "C1").await,
+            Ok(Some(10.into()))
+        );
+        assert_eq!(
+            ExchangeOperation::spread(orders_db.clone(), "C1").await,
+            Ok(Some(3.into()))
+        );
+        let depth = ExchangeOperation::depth(orders_db.clone(), "C1", 10)
+            .await
+            .unwrap();
+        assert_eq!(depth.bids.len(), 1);
+        assert_eq!(depth.asks.len(), 2);
+        assert_eq!(depth.asks[1].price, 12.into());
+        assert_eq!(depth.asks[1].amount, 5);
+        assert_eq!(
+            ExchangeOperation::best_bid(orders_db.clone(), "C9").await,
+            Err(ExchangeError::UnknownTicker)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_market_data_events() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let mut feed = ExchangeOperation::subscribe(orders_db.clone()).await;
+        let report = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", 10.into(), 5),
+        )
+        .await
+        .unwrap();
+        let _ = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", 8.into(), 2),
+        )
+        .await;
+        let _ =
+            ExchangeOperation::modify(orders_db.clone(), clients_db.clone(), 4, 8.into(), 1).await;
+        let _ = ExchangeOperation::cancel(orders_db.clone(), clients_db.clone(), 4).await;
+
+        let mut events = Vec::new();
+        while let Ok(data) = feed.try_recv() {
+            events.push(data);
+        }
+        // asks_setup published the two resting asks first
+        let sequences: Vec<u64> = events.iter().map(|data| data.sequence).collect();
+        assert_eq!(sequences, (3..=8).collect::<Vec<u64>>());
+        let events: Vec<MarketEvent> = events.into_iter().map(|data| data.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                MarketEvent::trade(&report.fills[0], Operation::Buy),
+                MarketEvent::LevelRemoved {
+                    ticker: "C1".to_string(),
+                    side: Operation::Sell,
+                    price: 10.into()
+                },
+                MarketEvent::OrderAdded {
+                    order_id: 4,
+                    ticker: "C1".to_string(),
+                    side: Operation::Buy,
+                    price: 8.into(),
+                    amount: 2
+                },
+                MarketEvent::OrderReduced {
+                    order_id: 4,
+                    ticker: "C1".to_string(),
+                    side: Operation::Buy,
+                    price: 8.into(),
+                    amount: 1
+                },
+                MarketEvent::OrderCancelled {
+                    order_id: 4,
+                    ticker: "C1".to_string(),
+                    side: Operation::Buy,
+                    price: 8.into(),
+                    amount: 1
+                },
+                MarketEvent::LevelRemoved {
+                    ticker: "C1".to_string(),
+                    side: Operation::Buy,
+                    price: 8.into()
+                },
+            ]
+        );
+        assert_eq!(orders_db.get_publisher().get_last_sequence(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_tick_and_lot_size() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let price = |price: &str| price.parse::<Price>().unwrap();
+        assert_eq!(
+            ExchangeOperation::set_ticker_rules(orders_db.clone(), "C9", price("0.5"), 1).await,
+            Err(ExchangeError::UnknownTicker)
+        );
+        assert_eq!(
+            ExchangeOperation::set_ticker_rules(orders_db.clone(), "C1", Price::ZERO, 1).await,
+            Err(ExchangeError::ZeroPrice)
+        );
+        ExchangeOperation::set_ticker_rules(orders_db.clone(), "C1", price("0.5"), 2)
+            .await
+            .unwrap();
+
+        for (order, reason) in [
+            (
+                Order::new("A", Operation::Buy, "C1", price("9.25"), 2),
+                ExchangeError::OffTick(price("0.5")),
+            ),
+            (
+                Order::new("A", Operation::Buy, "C1", price("9.5"), 3),
+                ExchangeError::OddLot(2),
+            ),
+            (
+                Order::new_market("A", Operation::Buy, "C1", 1),
+                ExchangeError::OddLot(2),
+            ),
+        ] {
+            let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+                .await
+                .unwrap();
+            assert_eq!(report.status, OrderStatus::Rejected(reason));
+        }
+        let report = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", price("9.5"), 4),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+        assert_eq!(
+            ExchangeOperation::modify(
+                orders_db.clone(),
+                clients_db.clone(),
+                report.order_id,
+                price("9.75"),
+                4
+            )
+            .await,
+            Err(ExchangeError::OffTick(price("0.5")))
+        );
+        // the resting asks at 10 and 12 predate the rules and still trade
+        let report = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", 12.into(), 6),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.filled_amount(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_decimal_prices() {
+        let (orders_db, clients_db) = asks_setup(0).await;
+        let price = |price: &str| price.parse::<Price>().unwrap();
+        ExchangeOperation::deposit(
+            orders_db.clone(),
+            clients_db.clone(),
+            "A",
+            None,
+            price("30.75"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ExchangeOperation::deposit(
+                orders_db.clone(),
+                clients_db.clone(),
+                "B",
+                Some("C1"),
+                price("2.5"),
+            )
+            .await,
+            Err(ExchangeError::ProblemWithNumber)
+        );
+        ExchangeOperation::deposit(
+            orders_db.clone(),
+            clients_db.clone(),
+            "B",
+            Some("C1"),
+            3.into(),
+        )
+        .await
+        .unwrap();
+        let _ = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("B", Operation::Sell, "C1", price("9.99"), 3),
+        )
+        .await;
+        let report = ExchangeOperation::operate(
+            orders_db.clone(),
+            clients_db.clone(),
+            Order::new("A", Operation::Buy, "C1", price("10.25"), 3),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.status, OrderStatus::Filled);
+        assert_eq!(report.fills[0].price, price("9.99"));
+        assert_eq!(clients_db.get("A").unwrap().get_balance(), price("0.78"));
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            Price::ZERO
+        );
+        assert_eq!(clients_db.get("B").unwrap().get_balance(), price("29.97"));
     }
 
-    fn get_mut_pair<'a, K, V>(conns: &'a mut HashMap<K, V>, a: &K, b: &K) -> (&'a mut V, &'a mut V)
-    where
-        K: Eq + std::hash::Hash,
-    {
-        unsafe {
-            let a = conns.get_mut(a).unwrap() as *mut _;
-            let b = conns.get_mut(b).unwrap() as *mut _;
-            // assert_ne!(a, b, "The two keys must not resolve to the same value");
-            (&mut *a, &mut *b)
+    #[tokio::test]
+    async fn test_huge_prices_do_not_panic() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let huge = Price::from_minor(u64::MAX);
+        for order in [
+            Order::new("A", Operation::Buy, "C1", huge, u32::MAX),
+            Order::new("A", Operation::Buy, "C1", huge, 1),
+            Order::new("B", Operation::Sell, "C1", huge, u32::MAX),
+        ] {
+            let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+                .await
+                .unwrap();
+            assert!(matches!(report.status, OrderStatus::Rejected(_)));
         }
-    }
+        let mut order = Order::new("A", Operation::Buy, "C1", 10.into(), u32::MAX);
+        order.set_time_in_force(TimeInForce::Fok);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.status,
+            OrderStatus::Rejected(ExchangeError::BuyerDoesntHaveEnoughMoney)
+        );
+        let mut order = Order::new_market("A", Operation::Buy, "C1", u32::MAX);
+        order.set_time_in_force(TimeInForce::Fok);
+        let report = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
 
-    fn calculate_difference_of_path<'a>(
-        order_operation: Operation,
-        keys: impl Iterator<Item = &'a u32>,
-        order_price: u32,
-    ) -> (u32, bool, bool) {
-        if order_operation == Operation::Sell {
-            let max_buying_price = match keys.max() {
-                None => {
-                    return (0, false, true);
-                }
-                Some(val) => *val,
-            };
-            return (max_buying_price, max_buying_price >= order_price, false);
-        }
-        let min_selling_price = match keys.min() {
-            None => {
-                return (0, false, true);
-            }
-            Some(val) => *val,
-        };
-        (min_selling_price, min_selling_price <= order_price, false)
+        // the book and the clients are as asks_setup left them
+        assert_eq!(orders_db.orders_count(), 2);
+        assert_eq!(clients_db.get("A").unwrap().get_balance(), 1000.into());
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            Price::ZERO
+        );
+        assert_eq!(clients_db.get("B").unwrap().get_reserved_stock("C1"), 10);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[tokio::test]
-    async fn test_addition_of_clients() {
-        let clients_db: Arc<Mutex<HashMap<ClientsName, Client>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let client1 = Client::new("A", 5);
-        let client2 = Client::new("B", 4);
-        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding {}", er);
-                ()
-            }
+    async fn test_failed_settlement_is_not_applied() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        let buyer = Client::new("A", 1000.into());
+        // B's balance overflows on any sale
+        let mut seller = Client::new("B", Price::from_minor(u64::MAX));
+        let _ = seller.update_stock_balance("C1", 10, Operation::Buy);
+        let mut other = Client::new("C", 0.into());
+        let _ = other.update_stock_balance("C1", 10, Operation::Buy);
+        for client in [buyer, seller, other] {
+            let _ = ExchangeOperation::add_client(clients_db.clone(), client).await;
         }
-        assert_eq!(clients_db.clone().lock().unwrap().len(), 1);
-        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding {}", er);
-                ()
-            }
+        for order in [
+            Order::new("C", Operation::Sell, "C1", 9.into(), 2),
+            Order::new("B", Operation::Sell, "C1", 10.into(), 5),
+            Order::new("C", Operation::Sell, "C1", 11.into(), 6),
+        ] {
+            let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order).await;
         }
-        assert_eq!(clients_db.clone().lock().unwrap().len(), 2);
+        let operate =
+            |order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
 
-        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
-            Ok(_) => {
-                println!("there should be error");
-                ()
-            }
-            Err(_) => (),
-        }
+        // the fill at 9 stays, B's order leaves the book, the rest is given back
+        let report = operate(Order::new("A", Operation::Buy, "C1", 11.into(), 10))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.fills[0].price, 9.into());
+        let buyer = clients_db.get("A").unwrap();
+        assert_eq!(buyer.get_balance(), 982.into());
+        assert_eq!(buyer.get_reserved_balance(), Price::ZERO);
+        assert_eq!(*buyer.get_amount_of_stock("C1"), 2);
+        let seller = clients_db.get("B").unwrap();
+        assert_eq!(seller.get_balance(), Price::from_minor(u64::MAX));
+        assert_eq!(*seller.get_amount_of_stock("C1"), 10);
+        assert_eq!(seller.get_reserved_stock("C1"), 0);
+        assert_eq!(orders_db.orders_count(), 1);
 
-        assert_eq!(clients_db.clone().lock().unwrap().len(), 2);
+        // the next order is not stuck behind B
+        let report = operate(Order::new_market("A", Operation::Buy, "C1", 3))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Filled);
+        assert_eq!(report.fills[0].price, 11.into());
     }
 
-    #[tokio::test]
-    async fn test_exchange() {
-        let orders_db: Arc<Mutex<HashMap<Ticker, HashMap<Operation, HashMap<Price, Vec<Order>>>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let clients_db: Arc<Mutex<HashMap<ClientsName, Client>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let mut client1 = Client::new("A", 1000);
-        let mut client2 = Client::new("B", 1000);
-        let _ = client2.update_stock_balance("C1", 10, Operation::Buy);
-        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
-        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding client {}", er);
-                ()
-            }
+    /// B offers 5 at each of 10, 11, 12 and 13.
+    async fn stops_setup() -> (OrdersDb, ClientsDb) {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        for name in ["A", "B", "C"] {
+            let mut client = Client::new(name, 1000.into());
+            client.deposit_stock("C1", 50).unwrap();
+            let _ = ExchangeOperation::add_client(clients_db.clone(), client).await;
         }
-        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding client {}", er);
-                ()
-            }
+        for price in 10..14 {
+            let order = Order::new("B", Operation::Sell, "C1", price.into(), 5);
+            let _ = ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order).await;
         }
-        let order1 = Order::new("B", Operation::Buy, "C1", 10, 10);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order1.clone())
+        (orders_db, clients_db)
+    }
+
+    #[tokio::test]
+    async fn test_stop_orders() {
+        let (orders_db, clients_db) = stops_setup().await;
+        let operate =
+            |order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+
+        let report = operate(Order::new_stop("C", Operation::Buy, "C1", 0.into(), 1))
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order1 {}", er);
-                ()
-            }
-        }
-        // println!("{:?}", exchange.get_open_orders());
+            .unwrap();
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            1
+            report.status,
+            OrderStatus::Rejected(ExchangeError::ZeroPrice)
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
 
-        let order2 = Order::new("A", Operation::Sell, "C1", 10, 10);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order2.clone())
+        let stop_sell = Order::new_stop_limit("C", Operation::Sell, "C1", 5.into(), 5.into(), 3);
+        let report = operate(stop_sell).await.unwrap();
+        assert_eq!(report.status, OrderStatus::New);
+        assert_eq!(clients_db.get("C").unwrap().get_reserved_stock("C1"), 3);
+        assert!(orders_db.get_order(report.order_id).is_some());
+        assert_eq!(orders_db.orders_count(), 4);
+        assert_eq!(
+            ExchangeOperation::modify(
+                orders_db.clone(),
+                clients_db.clone(),
+                report.order_id,
+                6.into(),
+                3
+            )
+            .await,
+            Err(ExchangeError::CannotModifyStop)
+        );
+        assert_eq!(clients_db.get("C").unwrap().get_reserved_stock("C1"), 3);
+        ExchangeOperation::cancel(orders_db.clone(), clients_db.clone(), report.order_id)
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order2 {}", er);
-                ()
-            }
-        }
+            .unwrap();
+        assert_eq!(clients_db.get("C").unwrap().get_reserved_stock("C1"), 0);
+
+        // one trade reaches both stops, the older one goes first
+        let first = operate(Order::new_stop("C", Operation::Buy, "C1", 11.into(), 2))
+            .await
+            .unwrap()
+            .order_id;
+        let second = operate(Order::new_stop("A", Operation::Buy, "C1", 10.into(), 2))
+            .await
+            .unwrap()
+            .order_id;
+        let report = operate(Order::new("A", Operation::Buy, "C1", 11.into(), 6))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Filled);
+        let triggered: Vec<(OrderId, OrderStatus)> = report
+            .triggered
+            .iter()
+            .map(|report| (report.order_id, report.status.clone()))
+            .collect();
+        assert_eq!(
+            triggered,
+            vec![(first, OrderStatus::Filled), (second, OrderStatus::Filled)]
+        );
+        assert_eq!(report.triggered[0].fills[0].price, 11.into());
+        assert!(orders_db.get_order(first).is_none());
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            0
+            ExchangeOperation::best_ask(orders_db.clone(), "C1").await,
+            Ok(Some(12.into()))
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
 
-        let clients_db1 = clients_db.lock().unwrap();
-        let client1_new = clients_db1.get("A").unwrap();
+        // the last trade was at 11, so this stop is reached already
+        let order = Order::new_stop_limit("C", Operation::Buy, "C1", 11.into(), 12.into(), 1);
+        let report = operate(order).await.unwrap();
+        assert_eq!(report.status, OrderStatus::Filled);
+        assert_eq!(report.fills[0].price, 12.into());
+    }
 
-        assert_eq!(client1_new.get_balance(), 1100);
-        assert_eq!(client1_new.get_amount_of_stock("C1"), &0);
+    #[tokio::test]
+    async fn test_stop_cascade() {
+        let (orders_db, clients_db) = stops_setup().await;
+        let operate =
+            |order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+        let mut stops = Vec::new();
+        for order in [
+            Order::new_stop("C", Operation::Buy, "C1", 11.into(), 5),
+            Order::new_stop_limit("C", Operation::Buy, "C1", 12.into(), 13.into(), 5),
+            Order::new_stop("A", Operation::Buy, "C1", 13.into(), 5),
+        ] {
+            stops.push(operate(order).await.unwrap().order_id);
+        }
+        assert_eq!(
+            clients_db.get("C").unwrap().get_reserved_balance(),
+            65.into()
+        );
 
-        let client2_new = clients_db1.get("B").unwrap();
+        let report = operate(Order::new("A", Operation::Buy, "C1", 10.into(), 5))
+            .await
+            .unwrap();
+        assert!(report.triggered.is_empty());
 
-        assert_eq!(client2_new.get_balance(), 900);
-        assert_eq!(client2_new.get_amount_of_stock("C1"), &20);
+        // 11 reaches the first stop, which trades up to 12 and reaches the
+        // second, which trades up to 13 and reaches the third
+        let report = operate(Order::new("A", Operation::Buy, "C1", 11.into(), 1))
+            .await
+            .unwrap();
+        let triggered: Vec<(OrderId, OrderStatus, u32)> = report
+            .triggered
+            .iter()
+            .map(|report| {
+                (
+                    report.order_id,
+                    report.status.clone(),
+                    report.filled_amount(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            triggered,
+            vec![
+                (stops[0], OrderStatus::Filled, 5),
+                (stops[1], OrderStatus::Filled, 5),
+                (stops[2], OrderStatus::Cancelled, 4),
+            ]
+        );
+        assert_eq!(orders_db.orders_count(), 0);
+        let client_c = clients_db.get("C").unwrap();
+        assert_eq!(
+            client_c.get_balance(),
+            Price::from(1000 - (4 * 11 + 12) - (4 * 12 + 13))
+        );
+        assert_eq!(client_c.get_reserved_balance(), 0.into());
+        assert_eq!(client_c.get_amount_of_stock("C1"), &60);
+        let client_a = clients_db.get("A").unwrap();
+        assert_eq!(client_a.get_amount_of_stock("C1"), &(50 + 5 + 1 + 4));
+        assert_eq!(client_a.get_reserved_balance(), 0.into());
     }
 
     #[tokio::test]
-    async fn test_no_exchange() {
-        let orders_db: Arc<Mutex<HashMap<Ticker, HashMap<Operation, HashMap<Price, Vec<Order>>>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let clients_db: Arc<Mutex<HashMap<ClientsName, Client>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let mut client1 = Client::new("A", 1000);
-        let mut client2 = Client::new("B", 1000);
-        let _ = client2.update_stock_balance("C2", 10, Operation::Buy);
-        let _ = client1.update_stock_balance("C1", 10, Operation::Buy);
-        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding client {}", er);
-                ()
-            }
-        }
-        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding client {}", er);
-                ()
-            }
+    async fn test_iceberg_orders() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), "C1").await;
+        for name in ["A", "B", "C"] {
+            let mut client = Client::new(name, 1000.into());
+            client.deposit_stock("C1", 50).unwrap();
+            let _ = ExchangeOperation::add_client(clients_db.clone(), client).await;
         }
-        let order1 = Order::new("B", Operation::Buy, "C2", 10, 10);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order1.clone())
+        let mut feed = ExchangeOperation::subscribe(orders_db.clone()).await;
+        let operate =
+            |order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+
+        let mut iceberg = Order::new("B", Operation::Sell, "C1", 10.into(), 12);
+        iceberg.set_display_amount(Some(0));
+        let report = operate(iceberg.clone()).await.unwrap();
+        assert_eq!(
+            report.status,
+            OrderStatus::Rejected(ExchangeError::ZeroAmount)
+        );
+        iceberg.set_display_amount(Some(5));
+        let iceberg_id = operate(iceberg).await.unwrap().order_id;
+        operate(Order::new("C", Operation::Sell, "C1", 10.into(), 3))
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order1 {}", er);
-                ()
-            }
-        }
-        // println!("{:?}", exchange.get_open_orders());
+            .unwrap();
+        let depth = ExchangeOperation::depth(orders_db.clone(), "C1", 1)
+            .await
+            .unwrap();
+        assert_eq!(depth.asks[0].amount, 5 + 3);
+        assert_eq!(clients_db.get("B").unwrap().get_reserved_stock("C1"), 12);
+
+        // the slice of 5 trades, then the refreshed slice queues behind C
+        let fills = |report: ExecutionReport| -> Vec<(String, u32)> {
+            report
+                .fills
+                .into_iter()
+                .map(|fill| (fill.maker, fill.amount))
+                .collect()
+        };
+        let report = operate(Order::new("A", Operation::Buy, "C1", 10.into(), 6))
+            .await
+            .unwrap();
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            1
+            fills(report),
+            vec![("B".to_string(), 5), ("C".to_string(), 1)]
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
+        let depth = ExchangeOperation::depth(orders_db.clone(), "C1", 1)
+            .await
+            .unwrap();
+        assert_eq!(depth.asks[0].amount, 2 + 5);
 
-        let order2 = Order::new("A", Operation::Sell, "C1", 10, 10);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order2.clone())
+        let report = operate(Order::new("A", Operation::Buy, "C1", 10.into(), 9))
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order2 {}", er);
-                ()
+            .unwrap();
+        assert_eq!(
+            fills(report),
+            vec![
+                ("C".to_string(), 2),
+                ("B".to_string(), 5),
+                ("B".to_string(), 2)
+            ]
+        );
+        assert_eq!(orders_db.orders_count(), 0);
+        let seller = clients_db.get("B").unwrap();
+        assert_eq!(seller.get_amount_of_stock("C1"), &38);
+        assert_eq!(seller.get_reserved_stock("C1"), 0);
+
+        // the feed only ever showed slices of the iceberg
+        let mut shown = Vec::new();
+        while let Ok(data) = feed.try_recv() {
+            if let MarketEvent::OrderAdded {
+                order_id, amount, ..
+            } = data.event
+            {
+                if order_id == iceberg_id {
+                    shown.push(amount);
+                }
             }
         }
-        println!("{:?}", orders_db.lock().unwrap());
+        assert_eq!(shown, vec![5, 5, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_post_only() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        ExchangeOperation::set_ticker_rules(orders_db.clone(), "C1", "0.5".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let operate =
+            |order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+        let post_only = |mut order: Order, post_only| {
+            order.set_post_only(post_only);
+            order
+        };
+        let status = |report: Result<ExecutionReport>| report.unwrap().status;
+
+        let order = Order::new("A", Operation::Buy, "C1", 10.into(), 2);
+        assert_eq!(
+            status(operate(post_only(order, PostOnly::Reject)).await),
+            OrderStatus::Rejected(ExchangeError::WouldTrade)
+        );
+        let order = Order::new_market("A", Operation::Buy, "C1", 2);
+        assert_eq!(
+            status(operate(post_only(order, PostOnly::Reprice)).await),
+            OrderStatus::Rejected(ExchangeError::PostOnlyCannotRest)
+        );
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            2
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
 
-        let order3 = Order::new("B", Operation::Sell, "C2", 11, 9);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order3.clone())
-            .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order3 {}", er);
-                ()
-            }
+        // one tick below the best ask of 10
+        let order = Order::new("A", Operation::Buy, "C1", 11.into(), 2);
+        let report = operate(post_only(order, PostOnly::Reprice)).await.unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+        let bid = orders_db.get_order(report.order_id).unwrap();
+        assert_eq!(bid.get_price(), "9.5".parse().unwrap());
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            19.into()
+        );
+
+        // one tick above the best bid
+        let order = Order::new("A", Operation::Sell, "C1", 9.into(), 1);
+        let report = operate(post_only(order, PostOnly::Reprice)).await.unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+        assert_eq!(
+            orders_db.get_order(report.order_id).unwrap().get_price(),
+            10.into()
+        );
+
+        // moving the bid across the book reprices it again
+        let report = ExchangeOperation::modify(
+            orders_db.clone(),
+            clients_db.clone(),
+            bid.get_id(),
+            12.into(),
+            2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+        assert_eq!(
+            orders_db.get_order(bid.get_id()).unwrap().get_price(),
+            "9.5".parse().unwrap()
+        );
+        assert_eq!(orders_db.orders_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_trading_status_and_price_band() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let operate =
+            |order: Order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+
+        for (status, reason) in [
+            (TradingStatus::Halted, ExchangeError::TickerHalted),
+            (TradingStatus::Closed, ExchangeError::TickerClosed),
+        ] {
+            ExchangeOperation::set_trading_status(orders_db.clone(), "C1", status)
+                .await
+                .unwrap();
+            let order = Order::new("A", Operation::Buy, "C1", 10.into(), 1);
+            let report = operate(order).await.unwrap();
+            assert_eq!(report.status, OrderStatus::Rejected(reason.clone()));
+            let modified =
+                ExchangeOperation::modify(orders_db.clone(), clients_db.clone(), 1, 11.into(), 5)
+                    .await;
+            assert_eq!(modified, Err(reason));
         }
+        ExchangeOperation::set_trading_status(orders_db.clone(), "C1", TradingStatus::Open)
+            .await
+            .unwrap();
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            3
+            ExchangeOperation::trading_status(orders_db.clone(), "C1").await,
+            Ok(TradingStatus::Open)
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
 
-        let order4 = Order::new("A", Operation::Buy, "C1", 9, 11);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order4.clone())
+        let band = PriceBand {
+            reference_price: 10.into(),
+            width_bps: 1000,
+        };
+        ExchangeOperation::set_price_band(orders_db.clone(), "C1", Some(band))
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order4 {}", er);
-                ()
-            }
-        }
+            .unwrap();
+        let order = Order::new("A", Operation::Buy, "C1", 12.into(), 1);
+        let report = operate(order).await.unwrap();
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            4
+            report.status,
+            OrderStatus::Rejected(ExchangeError::OutsidePriceBand)
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
+        // the ask at 12 is out of band, so the market order stops at 10
+        let report = operate(Order::new_market("A", Operation::Buy, "C1", 7))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(report.filled_amount(), 5);
+        // a bid below it still rests
+        let report = operate(Order::new("A", Operation::Buy, "C1", 11.into(), 1))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+
+        ExchangeOperation::set_price_band(orders_db.clone(), "C1", None)
+            .await
+            .unwrap();
+        let report = operate(Order::new_market("A", Operation::Buy, "C1", 1))
+            .await
+            .unwrap();
+        assert_eq!(report.fills[0].price, 12.into());
     }
 
     #[tokio::test]
-    async fn test_buy1() {
-        let orders_db: Arc<Mutex<HashMap<Ticker, HashMap<Operation, HashMap<Price, Vec<Order>>>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let clients_db: Arc<Mutex<HashMap<ClientsName, Client>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let client1 = Client::new("A", 10000);
-        let mut client2 = Client::new("B", 10000);
-        let _ = client2.update_stock_balance("C1", 100, Operation::Buy);
-        match ExchangeOperation::add_client(clients_db.clone(), client1.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding client {}", er);
-                ()
-            }
-        }
-        match ExchangeOperation::add_client(clients_db.clone(), client2.clone()).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with adding client {}", er);
-                ()
-            }
-        }
+    async fn test_circuit_breaker() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let _ =
+            ExchangeOperation::add_client(clients_db.clone(), Client::new("D", 1000.into())).await;
+        let operate =
+            |order: Order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+        operate(Order::new("A", Operation::Sell, "C1", 13.into(), 2))
+            .await
+            .unwrap();
+        ExchangeOperation::set_circuit_breaker(
+            orders_db.clone(),
+            "C1",
+            Some(CircuitBreaker::new(1000, 5)),
+        )
+        .await
+        .unwrap();
 
-        let order1 = Order::new("B", Operation::Sell, "C1", 10, 50);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order1.clone())
+        // the move from 10 to 12 halts the book before the last unit
+        let mut order = Order::new("D", Operation::Buy, "C1", 13.into(), 11);
+        order.set_time_in_force(TimeInForce::Fok);
+        let report = operate(order).await.unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert!(report.fills.is_empty());
+
+        let report = operate(Order::new("D", Operation::Buy, "C1", 13.into(), 11))
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order1 {}", er);
-                ()
-            }
-        }
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Cancelled);
+        assert_eq!(report.filled_amount(), 10);
+        assert_eq!(
+            ExchangeOperation::trading_status(orders_db.clone(), "C1").await,
+            Ok(TradingStatus::Halted)
+        );
+        assert_eq!(
+            clients_db.get("D").unwrap().get_reserved_balance(),
+            0.into()
+        );
+        let report = operate(Order::new("D", Operation::Buy, "C1", 13.into(), 1))
+            .await
+            .unwrap();
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            1
+            report.status,
+            OrderStatus::Rejected(ExchangeError::TickerHalted)
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
 
-        let order2 = Order::new("B", Operation::Sell, "C1", 15, 50);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order2.clone())
+        // resuming forgets the trades before the halt
+        ExchangeOperation::set_trading_status(orders_db.clone(), "C1", TradingStatus::Open)
             .await
-        {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order2 {}", er);
-                ()
-            }
-        }
+            .unwrap();
+        let report = operate(Order::new("D", Operation::Buy, "C1", 13.into(), 1))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Filled);
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            2
+            ExchangeOperation::trading_status(orders_db.clone(), "C1").await,
+            Ok(TradingStatus::Open)
         );
-        assert_eq!(clients_db.lock().unwrap().len(), 2);
+    }
 
-        let order3 = Order::new("A", Operation::Buy, "C1", 20, 75);
-        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order3.clone())
+    #[tokio::test]
+    async fn test_auctions() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let operate =
+            |order: Order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+        let end_auction = |status: TradingStatus| {
+            ExchangeOperation::end_auction(orders_db.clone(), clients_db.clone(), "C1", status)
+        };
+        ExchangeOperation::set_trading_status(orders_db.clone(), "C1", TradingStatus::Auction)
+            .await
+            .unwrap();
+
+        // the bid crosses both asks but only rests during the call
+        let report = operate(Order::new("A", Operation::Buy, "C1", 12.into(), 4))
+            .await
+            .unwrap();
+        assert_eq!(report.status, OrderStatus::Rested);
+        let report = operate(Order::new_market("A", Operation::Buy, "C1", 1))
+            .await
+            .unwrap();
+        assert_eq!(
+            report.status,
+            OrderStatus::Rejected(ExchangeError::CannotRestInAuction)
+        );
+        assert_eq!(
+            ExchangeOperation::set_trading_status(orders_db.clone(), "C1", TradingStatus::Open)
+                .await,
+            Err(ExchangeError::AuctionInProgress)
+        );
+        // 4 trade at 10 and at 12, 10 leaves less unmatched
+        let indicative = ExchangeOperation::indicative_auction(orders_db.clone(), "C1")
             .await
+            .unwrap();
+        assert_eq!(
+            indicative,
+            Some(AuctionPrice {
+                price: 10.into(),
+                volume: 4,
+                imbalance: 1,
+                imbalance_side: Some(Operation::Sell),
+            })
+        );
+
+        let report = end_auction(TradingStatus::Open).await.unwrap();
+        assert_eq!(report.auction, indicative);
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.fills[0].price, 10.into());
+        assert_eq!(report.fills[0].amount, 4);
+        assert_eq!(report.fills[0].maker_order_id, 1);
         {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error with operating order3 {}", er);
-                ()
-            }
+            let client_a = clients_db.get("A").unwrap();
+            assert_eq!(client_a.get_balance(), 960.into());
+            assert_eq!(client_a.get_reserved_balance(), 0.into());
         }
+        assert_eq!(
+            ExchangeOperation::trading_status(orders_db.clone(), "C1").await,
+            Ok(TradingStatus::Open)
+        );
+        assert_eq!(
+            ExchangeOperation::best_ask(orders_db.clone(), "C1").await,
+            Ok(Some(10.into()))
+        );
+        assert_eq!(
+            end_auction(TradingStatus::Open).await,
+            Err(ExchangeError::NoAuction)
+        );
 
-        let clients_db1 = clients_db.lock().unwrap();
-        let client_a = clients_db1.get("A").unwrap();
-        assert_eq!(client_a.get_balance(), 10000 - (10 * 50 + 15 * 25));
+        // closing auction, the only cross is A with itself
+        ExchangeOperation::set_trading_status(orders_db.clone(), "C1", TradingStatus::Auction)
+            .await
+            .unwrap();
+        operate(Order::new("A", Operation::Sell, "C1", 9.into(), 1))
+            .await
+            .unwrap();
+        let bid = operate(Order::new("A", Operation::Buy, "C1", 10.into(), 1))
+            .await
+            .unwrap();
+        let report = end_auction(TradingStatus::Closed).await.unwrap();
+        assert_eq!(report.auction.unwrap().price, 9.into());
+        assert!(report.fills.is_empty());
+        assert_eq!(orders_db.get_order(bid.order_id), None);
+        assert_eq!(
+            clients_db.get("A").unwrap().get_reserved_balance(),
+            0.into()
+        );
+        assert_eq!(
+            ExchangeOperation::trading_status(orders_db.clone(), "C1").await,
+            Ok(TradingStatus::Closed)
+        );
+    }
 
-        let client_b = clients_db1.get("B").unwrap();
-        assert_eq!(client_b.get_balance(), 10000 + (10 * 50 + 15 * 25));
+    #[tokio::test]
+    async fn test_auction_within_band_trips_breaker() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let operate =
+            |order: Order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+        ExchangeOperation::set_circuit_breaker(
+            orders_db.clone(),
+            "C1",
+            Some(CircuitBreaker::new(1000, 5)),
+        )
+        .await
+        .unwrap();
+        operate(Order::new("A", Operation::Buy, "C1", 10.into(), 1))
+            .await
+            .unwrap();
+        ExchangeOperation::set_trading_status(orders_db.clone(), "C1", TradingStatus::Auction)
+            .await
+            .unwrap();
+        // the ask at 10 is left outside the band
+        ExchangeOperation::set_price_band(
+            orders_db.clone(),
+            "C1",
+            Some(PriceBand {
+                reference_price: 12.into(),
+                width_bps: 1000,
+            }),
+        )
+        .await
+        .unwrap();
+        operate(Order::new("A", Operation::Buy, "C1", 12.into(), 4))
+            .await
+            .unwrap();
 
+        // 10 would trade as much with no imbalance, but only 12 is in the band
+        let report = ExchangeOperation::end_auction(
+            orders_db.clone(),
+            clients_db.clone(),
+            "C1",
+            TradingStatus::Open,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.auction.unwrap().price, 12.into());
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.fills[0].price, 12.into());
+        assert_eq!(report.fills[0].amount, 4);
+        assert_eq!(clients_db.get("A").unwrap().get_balance(), 942.into());
+        // the move from 10 to 12 halts the ticker instead of opening it
+        assert_eq!(
+            ExchangeOperation::trading_status(orders_db.clone(), "C1").await,
+            Ok(TradingStatus::Halted)
+        );
         assert_eq!(
-            orders_db
-                .lock()
-                .unwrap()
-                .values()
-                .flat_map(HashMap::values)
-                .flat_map(HashMap::values)
-                .flatten()
-                .count(),
-            1
+            ExchangeOperation::best_ask(orders_db.clone(), "C1").await,
+            Ok(Some(12.into()))
         );
-        assert_eq!(clients_db1.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_order_index_follows_the_books() {
+        let (orders_db, clients_db) = asks_setup(1000).await;
+        let indexed = || {
+            let mut ids: Vec<OrderId> = orders_db.tickers.read().unwrap().keys().copied().collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(indexed(), vec![1, 2]);
+
+        let operate =
+            |order: Order| ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order);
+        operate(Order::new("A", Operation::Buy, "C1", 10.into(), 5))
+            .await
+            .unwrap();
+        let stop = operate(Order::new_stop("A", Operation::Buy, "C1", 20.into(), 1))
+            .await
+            .unwrap();
+        assert_eq!(indexed(), vec![2, stop.order_id]);
+        ExchangeOperation::modify(orders_db.clone(), clients_db.clone(), 2, 11.into(), 5)
+            .await
+            .unwrap();
+        ExchangeOperation::cancel(orders_db.clone(), clients_db.clone(), stop.order_id)
+            .await
+            .unwrap();
+        assert_eq!(indexed(), vec![2]);
+        assert_eq!(orders_db.get_order(2).unwrap().get_price(), 11.into());
     }
 }
@@ -0,0 +1,630 @@
+use crate::{ClientsDb, ExchangeError, OrdersDb, Request, Response, Result, Snapshot};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+
+pub type JournalDb = Arc<JournalBase>;
+
+/// When appended entries are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// After every entry, nothing acknowledged is lost.
+    #[default]
+    Always,
+    /// After every n entries, a crash loses at most n - 1 of them.
+    Every(usize),
+    /// Left to the operating system.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = ExchangeError;
+
+    /// `always`, `never` or a number of entries.
+    fn from_str(input: &str) -> Result<FsyncPolicy> {
+        match input {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match input.parse::<usize>() {
+                Ok(0) | Err(_) => Err(ExchangeError::ProblemWithNumber),
+                Ok(1) => Ok(FsyncPolicy::Always),
+                Ok(n) => Ok(FsyncPolicy::Every(n)),
+            },
+        }
+    }
+}
+
+/// Append-only log of the requests that change the exchange, one line per
+/// entry: `sequence crc32 json`. The checksum covers the sequence and the
+/// json, so torn or altered entries are detected on open.
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    last_sequence: u64,
+    unsynced: usize,
+    /// Directory and number of entries between snapshots.
+    snapshots: Option<(PathBuf, u64)>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it when missing, and returns it
+    /// with every entry it holds. A damaged last line is a write cut short
+    /// by a crash and is cut off; damage before the last line is an error.
+    /// Sequences only have to increase: entries lost from an unsynced tail
+    /// leave a gap once a snapshot covered them, see `recover`.
+    pub fn open(path: &Path, policy: FsyncPolicy) -> Result<(Journal, Vec<(u64, Request)>)> {
+        let file_error = |_| ExchangeError::ProblemWithFile(path.display().to_string());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(file_error)?;
+
+        let mut entries = Vec::new();
+        let mut good_length = 0;
+        let mut damaged = false;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(file_error)?;
+            if read == 0 {
+                break;
+            }
+            let last_sequence = entries.last().map_or(0, |(sequence, _)| *sequence);
+            if damaged {
+                return Err(ExchangeError::CorruptJournal(last_sequence));
+            }
+            match Self::decode(&line) {
+                Some((sequence, request)) if sequence > last_sequence => {
+                    entries.push((sequence, request));
+                    good_length += read as u64;
+                }
+                _ => damaged = true,
+            }
+        }
+        if damaged {
+            file.set_len(good_length).map_err(file_error)?;
+            file.seek(SeekFrom::End(0)).map_err(file_error)?;
+        }
+
+        let journal = Journal {
+            file,
+            path: path.to_path_buf(),
+            policy,
+            last_sequence: entries.last().map_or(0, |(sequence, _)| *sequence),
+            unsynced: 0,
+            snapshots: None,
+        };
+        Ok((journal, entries))
+    }
+
+    pub fn get_last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Asks for a snapshot into `dir` after every `every` entries.
+    pub fn set_snapshots(&mut self, dir: PathBuf, every: u64) {
+        self.snapshots = Some((dir, every.max(1)));
+    }
+
+    /// Where to write a snapshot, when one is due after entry `sequence`.
+    pub fn snapshot_due(&self, sequence: u64) -> Option<&Path> {
+        match &self.snapshots {
+            Some((dir, every)) if sequence.is_multiple_of(*every) => Some(dir),
+            _ => None,
+        }
+    }
+
+    /// Restores the newest snapshot in `snapshot_dir`, if there is one, and
+    /// replays the journal entries written after it. Returns the journal
+    /// and the number of entries replayed.
+    ///
+    /// Without fsync on every entry the snapshot can cover entries the
+    /// journal lost in a crash. New entries then continue after the
+    /// snapshot, so their sequences never repeat ones it covered. Entries
+    /// missing after the snapshot are an error.
+    pub async fn recover(
+        path: &Path,
+        policy: FsyncPolicy,
+        snapshot_dir: Option<&Path>,
+        orders_db: OrdersDb,
+        clients_db: ClientsDb,
+    ) -> Result<(Journal, usize)> {
+        let (mut journal, mut entries) = Journal::open(path, policy)?;
+        let snapshot = match snapshot_dir {
+            Some(dir) => Snapshot::load_latest(dir)?,
+            None => None,
+        };
+        let mut covered = 0;
+        if let Some(snapshot) = snapshot {
+            covered = snapshot.journal_sequence;
+            snapshot.restore(&orders_db, &clients_db)?;
+            entries.retain(|(sequence, _)| *sequence > covered);
+            journal.last_sequence = journal.last_sequence.max(covered);
+        }
+        for (expected, (sequence, _)) in (covered + 1..).zip(&entries) {
+            if *sequence != expected {
+                return Err(ExchangeError::JournalGap(expected - 1));
+            }
+        }
+        let replayed = entries.len();
+        Self::replay(entries, orders_db, clients_db).await;
+        Ok((journal, replayed))
+    }
+
+    /// Runs journaled requests again, in order. Requests are deterministic,
+    /// so this rebuilds the state they left, rejections included.
+    pub async fn replay(entries: Vec<(u64, Request)>, orders_db: OrdersDb, clients_db: ClientsDb) {
+        for (_, request) in entries {
+            request.execute(orders_db.clone(), clients_db.clone()).await;
+        }
+    }
+
+    /// Writes the request and, depending on the policy, syncs it to disk.
+    /// Returns the sequence number of the entry.
+    pub fn append(&mut self, request: &Request) -> Result<u64> {
+        let sequence = self.last_sequence + 1;
+        let line = Self::encode(sequence, request);
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|_| self.file_error())?;
+        self.last_sequence = sequence;
+        self.unsynced += 1;
+        match self.policy {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Every(n) if self.unsynced >= n => self.sync()?,
+            _ => (),
+        }
+        Ok(sequence)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data().map_err(|_| self.file_error())?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn file_error(&self) -> ExchangeError {
+        ExchangeError::ProblemWithFile(self.path.display().to_string())
+    }
+
+    fn checksum(sequence: u64, json: &str) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(sequence.to_string().as_bytes());
+        hasher.update(b" ");
+        hasher.update(json.as_bytes());
+        hasher.finalize()
+    }
+
+    fn encode(sequence: u64, request: &Request) -> String {
+        let json = serde_json::to_string(request).unwrap_or_default();
+        format!(
+            "{} {:08x} {}\n",
+            sequence,
+            Self::checksum(sequence, &json),
+            json
+        )
+    }
+
+    fn decode(line: &str) -> Option<(u64, Request)> {
+        let line = line.strip_suffix('\n')?;
+        let mut parts = line.splitn(3, ' ');
+        let sequence = parts.next()?.parse::<u64>().ok()?;
+        let checksum = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let json = parts.next()?;
+        if Self::checksum(sequence, json) != checksum {
+            return None;
+        }
+        Some((sequence, serde_json::from_str(json).ok()?))
+    }
+}
+
+/// The journal shared by every session. It is only locked to append an
+/// entry; requests run outside it, so requests for different tickers run
+/// at the same time.
+#[derive(Debug)]
+pub struct JournalBase {
+    journal: Mutex<Journal>,
+    /// One lock per ticker, held by a request from its append until it has
+    /// run, so every book's requests are in the journal in the order they
+    /// changed the book.
+    books: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Shared by requests from their append until they have run, exclusive
+    /// while a snapshot is taken, so a snapshot holds exactly the entries
+    /// appended before it.
+    running: RwLock<()>,
+}
+
+impl JournalBase {
+    pub fn new(journal: Journal) -> Self {
+        JournalBase {
+            journal: Mutex::new(journal),
+            books: std::sync::Mutex::default(),
+            running: RwLock::new(()),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, Journal> {
+        self.journal.lock().await
+    }
+
+    /// Appends the request and runs it. A request that cannot be appended
+    /// does not run. Snapshots due after the entry are written once it has
+    /// run, on the blocking thread pool.
+    pub async fn execute(
+        &self,
+        request: Request,
+        orders_db: OrdersDb,
+        clients_db: ClientsDb,
+    ) -> Response {
+        let running = self.running.read().await;
+        let book = request.ticker(&orders_db).map(|ticker| {
+            let mut books = self.books.lock().unwrap();
+            Arc::clone(books.entry(ticker).or_default())
+        });
+        let book = match &book {
+            Some(book) => Some(book.lock().await),
+            None => None,
+        };
+        let sequence = match self.journal.lock().await.append(&request) {
+            Ok(sequence) => sequence,
+            Err(er) => return er.into(),
+        };
+        let response = request.execute(orders_db.clone(), clients_db.clone()).await;
+        drop(book);
+        drop(running);
+        self.snapshot(sequence, &orders_db, &clients_db).await;
+        response
+    }
+
+    async fn snapshot(&self, sequence: u64, orders_db: &OrdersDb, clients_db: &ClientsDb) {
+        let dir = match self.journal.lock().await.snapshot_due(sequence) {
+            Some(dir) => dir.to_path_buf(),
+            None => return,
+        };
+        let snapshot = {
+            let _running = self.running.write().await;
+            let sequence = self.journal.lock().await.get_last_sequence();
+            Snapshot::take(orders_db, clients_db, sequence)
+        };
+        let sequence = snapshot.journal_sequence;
+        let saved = tokio::task::spawn_blocking(move || snapshot.save(&dir))
+            .await
+            .unwrap_or_else(|er| Err(ExchangeError::ProblemWithFile(er.to_string())));
+        if let Err(er) = saved {
+            eprintln!("snapshot after journal entry {} failed: {}", sequence, er);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("victorparamonov_{}.journal", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn deposit(amount: u32) -> Request {
+        Request::Deposit {
+            client: "C1".to_string(),
+            ticker: None,
+            amount: amount.into(),
+        }
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let path = temp_path("reopen");
+        let (mut journal, entries) = Journal::open(&path, FsyncPolicy::Every(2)).unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(journal.append(&deposit(1)).unwrap(), 1);
+        assert_eq!(journal.append(&deposit(2)).unwrap(), 2);
+        drop(journal);
+
+        let (mut journal, entries) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(entries, vec![(1, deposit(1)), (2, deposit(2))]);
+        assert_eq!(journal.append(&deposit(3)).unwrap(), 3);
+        drop(journal);
+        let (journal, entries) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(journal.get_last_sequence(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_last_entry_is_cut_off() {
+        let path = temp_path("torn");
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        journal.append(&deposit(1)).unwrap();
+        journal.append(&deposit(2)).unwrap();
+        drop(journal);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 5]).unwrap();
+
+        let (mut journal, entries) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(entries, vec![(1, deposit(1))]);
+        assert_eq!(journal.append(&deposit(5)).unwrap(), 2);
+        drop(journal);
+        let (_, entries) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(entries, vec![(1, deposit(1)), (2, deposit(5))]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_entry_is_detected() {
+        let path = temp_path("corrupt");
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        for amount in 1..=3 {
+            journal.append(&deposit(amount)).unwrap();
+        }
+        drop(journal);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(
+            &path,
+            content.replacen("\"amount\":\"2\"", "\"amount\":\"9\"", 1),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Journal::open(&path, FsyncPolicy::Always).err(),
+            Some(ExchangeError::CorruptJournal(1))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fsync_policy_from_str() {
+        assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+        assert_eq!("never".parse(), Ok(FsyncPolicy::Never));
+        assert_eq!("100".parse(), Ok(FsyncPolicy::Every(100)));
+        assert_eq!(
+            "0".parse::<FsyncPolicy>(),
+            Err(ExchangeError::ProblemWithNumber)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recovery_rebuilds_state() {
+        let path = temp_path("recovery");
+        let journal: JournalDb = Arc::new(JournalBase::new(
+            Journal::open(&path, FsyncPolicy::Always).unwrap().0,
+        ));
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for line in [
+            r#"{"type":"add_ticker","ticker":"A"}"#,
+            r#"{"type":"add_client","name":"S","stocks":{"A":10}}"#,
+            r#"{"type":"add_client","name":"B","balance":100}"#,
+            r#"{"type":"place_order","client":"S","side":"sell","ticker":"A","price":5,"amount":4}"#,
+            r#"{"type":"place_order","client":"B","side":"buy","ticker":"A","price":5,"amount":3}"#,
+            r#"{"type":"place_order","client":"B","side":"buy","ticker":"A","price":50,"amount":3}"#,
+            r#"{"type":"query_client","name":"B"}"#,
+        ] {
+            crate::respond(orders_db.clone(), clients_db.clone(), Some(&journal), line).await;
+        }
+        assert_eq!(journal.lock().await.get_last_sequence(), 6);
+        drop(journal);
+
+        let (journal, entries) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(journal.get_last_sequence(), 6);
+        let recovered_orders = OrdersDb::default();
+        let recovered_clients = ClientsDb::default();
+        Journal::replay(entries, recovered_orders.clone(), recovered_clients.clone()).await;
+        fs::remove_file(&path).unwrap();
+
+        for name in ["S", "B"] {
+            let client = clients_db.get(name).unwrap();
+            let recovered = recovered_clients.get(name).unwrap();
+            assert_eq!(recovered.get_balance(), client.get_balance());
+            assert_eq!(
+                recovered.get_amount_of_stock("A"),
+                client.get_amount_of_stock("A")
+            );
+            assert_eq!(
+                recovered.get_reserved_stock("A"),
+                client.get_reserved_stock("A")
+            );
+        }
+        assert_eq!(recovered_orders.orders_count(), 1);
+        assert_eq!(recovered_orders.get_order(1), orders_db.get_order(1));
+        // the rejected order used an id too
+        assert!(recovered_orders.get_order(3).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recovery_from_snapshot_and_suffix() {
+        let path = temp_path("snapshot_suffix");
+        let dir = std::env::temp_dir().join("victorparamonov_journal_snapshots");
+        let _ = fs::remove_dir_all(&dir);
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        journal.set_snapshots(dir.clone(), 5);
+        let journal: JournalDb = Arc::new(JournalBase::new(journal));
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for line in [
+            r#"{"type":"add_ticker","ticker":"A"}"#,
+            r#"{"type":"add_client","name":"S","stocks":{"A":10}}"#,
+            r#"{"type":"place_order","client":"S","side":"sell","ticker":"A","price":5,"amount":4}"#,
+            r#"{"type":"add_client","name":"B","balance":100}"#,
+            r#"{"type":"place_order","client":"B","side":"buy","ticker":"A","price":5,"amount":3}"#,
+            r#"{"type":"place_order","client":"S","side":"sell","ticker":"A","price":6,"amount":1}"#,
+        ] {
+            crate::respond(orders_db.clone(), clients_db.clone(), Some(&journal), line).await;
+        }
+        drop(journal);
+        let snapshot = Snapshot::load_latest(&dir).unwrap().unwrap();
+        assert_eq!(snapshot.journal_sequence, 5);
+
+        let recovered_orders = OrdersDb::default();
+        let recovered_clients = ClientsDb::default();
+        let (journal, replayed) = Journal::recover(
+            &path,
+            FsyncPolicy::Never,
+            Some(&dir),
+            recovered_orders.clone(),
+            recovered_clients.clone(),
+        )
+        .await
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(journal.get_last_sequence(), 6);
+        assert_eq!(replayed, 1);
+        assert_eq!(
+            Snapshot::take(&recovered_orders, &recovered_clients, 6),
+            Snapshot::take(&orders_db, &clients_db, 6)
+        );
+        let client = recovered_clients.get("S").unwrap();
+        assert_eq!(client.get_balance(), 15.into());
+        assert_eq!(client.get_reserved_stock("A"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_ahead_of_the_journal() {
+        let path = temp_path("snapshot_ahead");
+        let dir = std::env::temp_dir().join("victorparamonov_journal_snapshots_ahead");
+        let _ = fs::remove_dir_all(&dir);
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        journal.set_snapshots(dir.clone(), 3);
+        let journal: JournalDb = Arc::new(JournalBase::new(journal));
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for line in [
+            r#"{"type":"add_client","name":"C1"}"#,
+            r#"{"type":"deposit","client":"C1","amount":1}"#,
+            r#"{"type":"deposit","client":"C1","amount":2}"#,
+        ] {
+            crate::respond(orders_db.clone(), clients_db.clone(), Some(&journal), line).await;
+        }
+        drop(journal);
+        // the crash lost the unsynced last entry, the snapshot has it
+        let content = fs::read_to_string(&path).unwrap();
+        let kept: String = content.split_inclusive('\n').take(2).collect();
+        fs::write(&path, kept).unwrap();
+
+        let recover = || async {
+            let orders_db = OrdersDb::default();
+            let clients_db = ClientsDb::default();
+            let (journal, replayed) = Journal::recover(
+                &path,
+                FsyncPolicy::Never,
+                Some(&dir),
+                orders_db.clone(),
+                clients_db.clone(),
+            )
+            .await
+            .unwrap();
+            (journal, replayed, orders_db, clients_db)
+        };
+        let (journal, replayed, orders_db, clients_db) = recover().await;
+        assert_eq!(replayed, 0);
+        assert_eq!(journal.get_last_sequence(), 3);
+        let journal: JournalDb = Arc::new(JournalBase::new(journal));
+        let line = r#"{"type":"deposit","client":"C1","amount":4}"#;
+        crate::respond(orders_db, clients_db, Some(&journal), line).await;
+        assert_eq!(journal.lock().await.get_last_sequence(), 4);
+        drop(journal);
+
+        // the new entry is not taken for one the snapshot covers
+        let (journal, replayed, _, clients_db) = recover().await;
+        assert_eq!(replayed, 1);
+        assert_eq!(journal.get_last_sequence(), 4);
+        assert_eq!(clients_db.get("C1").unwrap().get_balance(), 7.into());
+
+        // entries missing after the snapshot cannot be recovered
+        fs::remove_dir_all(&dir).unwrap();
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        journal.append(&deposit(8)).unwrap();
+        drop(journal);
+        let result = Journal::recover(
+            &path,
+            FsyncPolicy::Never,
+            None,
+            OrdersDb::default(),
+            ClientsDb::default(),
+        )
+        .await;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err(), Some(ExchangeError::JournalGap(2)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_tickers_run_at_the_same_time_and_replay() {
+        let path = temp_path("parallel");
+        let (journal, _) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let journal: JournalDb = Arc::new(JournalBase::new(journal));
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for line in [
+            r#"{"type":"add_ticker","ticker":"A"}"#,
+            r#"{"type":"add_ticker","ticker":"B"}"#,
+            r#"{"type":"add_client","name":"SA","stocks":{"A":100}}"#,
+            r#"{"type":"add_client","name":"BA","balance":1000}"#,
+            r#"{"type":"add_client","name":"SB","stocks":{"B":100}}"#,
+            r#"{"type":"add_client","name":"BB","balance":1000}"#,
+        ] {
+            crate::respond(orders_db.clone(), clients_db.clone(), Some(&journal), line).await;
+        }
+        // each ticker has its own clients, so only the order within a
+        // ticker matters
+        let tasks: Vec<_> = ["A", "B"]
+            .into_iter()
+            .map(|ticker| {
+                let (orders_db, clients_db, journal) =
+                    (orders_db.clone(), clients_db.clone(), journal.clone());
+                tokio::spawn(async move {
+                    for price in 1..=20 {
+                        for (client, side) in [("S", "sell"), ("B", "buy")] {
+                            let line = format!(
+                                r#"{{"type":"place_order","client":"{}{}","side":"{}","ticker":"{}","price":{},"amount":2}}"#,
+                                client,
+                                ticker,
+                                side,
+                                ticker,
+                                21 - price
+                            );
+                            crate::respond(
+                                orders_db.clone(),
+                                clients_db.clone(),
+                                Some(&journal),
+                                &line,
+                            )
+                            .await;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(journal.lock().await.get_last_sequence(), 86);
+        drop(journal);
+
+        let (_, entries) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        fs::remove_file(&path).unwrap();
+        let recovered_orders = OrdersDb::default();
+        let recovered_clients = ClientsDb::default();
+        Journal::replay(entries, recovered_orders.clone(), recovered_clients.clone()).await;
+        assert_eq!(
+            Snapshot::take(&recovered_orders, &recovered_clients, 0).clients,
+            Snapshot::take(&orders_db, &clients_db, 0).clients
+        );
+        assert_eq!(
+            Snapshot::take(&recovered_orders, &recovered_clients, 0).books,
+            Snapshot::take(&orders_db, &clients_db, 0).books
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Fill, Operation, Order, OrderId, Price, TradingStatus};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events kept by a lagging subscriber before it starts missing some.
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// One change of the public market state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketEvent {
+    Trade {
+        ticker: String,
+        price: Price,
+        amount: u32,
+        maker_order_id: OrderId,
+        taker_order_id: OrderId,
+        /// Side of the incoming order.
+        aggressor: Operation,
+    },
+    /// An order started resting on the book, or an iceberg order showed
+    /// its next slice at the back of its level. `amount` is what shows.
+    OrderAdded {
+        order_id: OrderId,
+        ticker: String,
+        side: Operation,
+        price: Price,
+        amount: u32,
+    },
+    /// A resting order got smaller without trading, `amount` is what is
+    /// left showing.
+    OrderReduced {
+        order_id: OrderId,
+        ticker: String,
+        side: Operation,
+        price: Price,
+        amount: u32,
+    },
+    /// A resting order was taken off without trading.
+    OrderCancelled {
+        order_id: OrderId,
+        ticker: String,
+        side: Operation,
+        price: Price,
+        amount: u32,
+    },
+    /// The last order of a price level left the book.
+    LevelRemoved {
+        ticker: String,
+        side: Operation,
+        price: Price,
+    },
+    /// An admin or the circuit breaker changed the trading status.
+    StatusChanged {
+        ticker: String,
+        status: TradingStatus,
+    },
+}
+
+impl MarketEvent {
+    pub fn trade(fill: &Fill, aggressor: Operation) -> Self {
+        MarketEvent::Trade {
+            ticker: fill.ticker.clone(),
+            price: fill.price,
+            amount: fill.amount,
+            maker_order_id: fill.maker_order_id,
+            taker_order_id: fill.taker_order_id,
+            aggressor,
+        }
+    }
+
+    pub fn order_added(order: &Order) -> Self {
+        MarketEvent::OrderAdded {
+            order_id: order.get_id(),
+            ticker: order.get_ticker().to_string(),
+            side: order.get_operation(),
+            price: order.get_price(),
+            amount: order.get_visible_amount(),
+        }
+    }
+
+    pub fn order_reduced(order: &Order) -> Self {
+        MarketEvent::OrderReduced {
+            order_id: order.get_id(),
+            ticker: order.get_ticker().to_string(),
+            side: order.get_operation(),
+            price: order.get_price(),
+            amount: order.get_visible_amount(),
+        }
+    }
+
+    pub fn order_cancelled(order: &Order) -> Self {
+        MarketEvent::OrderCancelled {
+            order_id: order.get_id(),
+            ticker: order.get_ticker().to_string(),
+            side: order.get_operation(),
+            price: order.get_price(),
+            amount: order.get_visible_amount(),
+        }
+    }
+}
+
+/// A published event. Sequence numbers start at 1 and have no holes, so a
+/// subscriber that sees one skipped has missed events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MarketData {
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub event: MarketEvent,
+}
+
+/// Numbers market events and broadcasts them to every subscriber.
+#[derive(Debug)]
+pub struct Publisher {
+    sender: broadcast::Sender<MarketData>,
+    last_sequence: u64,
+}
+
+impl Default for Publisher {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Publisher {
+            sender,
+            last_sequence: 0,
+        }
+    }
+}
+
+impl Publisher {
+    /// Continues numbering after `last_sequence`, e.g. after a restart.
+    pub fn starting_at(last_sequence: u64) -> Self {
+        Publisher {
+            last_sequence,
+            ..Publisher::default()
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MarketData> {
+        self.sender.subscribe()
+    }
+
+    pub fn get_last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Events are numbered even when nobody listens.
+    pub fn publish(&mut self, event: MarketEvent) {
+        self.last_sequence += 1;
+        let _ = self.sender.send(MarketData {
+            sequence: self.last_sequence,
+            event,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_numbers() {
+        let mut publisher = Publisher::default();
+        let event = MarketEvent::LevelRemoved {
+            ticker: "A".to_string(),
+            side: Operation::Buy,
+            price: 10.into(),
+        };
+        publisher.publish(event.clone());
+        let mut receiver = publisher.subscribe();
+        publisher.publish(event.clone());
+        publisher.publish(event.clone());
+        assert_eq!(publisher.get_last_sequence(), 3);
+        assert_eq!(receiver.try_recv().unwrap().sequence, 2);
+        let data = receiver.try_recv().unwrap();
+        assert_eq!(data.sequence, 3);
+        assert_eq!(data.event, event);
+        assert_eq!(
+            serde_json::to_value(&data).unwrap(),
+            serde_json::json!({"sequence": 3, "type": "level_removed", "ticker": "A", "side": "buy", "price": "10"})
+        );
+    }
+}
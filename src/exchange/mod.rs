@@ -1,5 +1,7 @@
 mod exchange_operation;
-pub use exchange_operation::{ClientsDb, ExchangeOperation, OrdersDb};
+pub use exchange_operation::{
+    BookDb, ClientsBase, ClientsDb, ExchangeOperation, OrdersBase, OrdersDb,
+};
 
 mod clients;
 pub use clients::Client;
@@ -7,5 +9,34 @@ pub use clients::Client;
 mod errors;
 pub use errors::{ExchangeError, Result};
 
+mod journal;
+pub use journal::{FsyncPolicy, Journal, JournalBase, JournalDb};
+
+mod money;
+pub use money::{Money, Price, DECIMALS};
+
+mod market_data;
+pub use market_data::{MarketData, MarketEvent, Publisher};
+
+mod order_book;
+pub use order_book::{Depth, OrderBook, PriceLevel};
+
 mod orders;
-pub use orders::{Operation, Order};
+pub use orders::{
+    Operation, Order, OrderId, OrderType, PostOnly, SelfTradePrevention, TimeInForce,
+};
+
+mod trading;
+pub use trading::{auction_price, AuctionPrice, CircuitBreaker, PriceBand, TradingStatus};
+
+mod trigger_book;
+pub use trigger_book::TriggerBook;
+
+mod snapshot;
+pub use snapshot::{BookSnapshot, Snapshot, SNAPSHOT_VERSION};
+
+mod reports;
+pub use reports::{AuctionReport, ExecutionReport, Fill, OrderStatus};
+
+mod protocol;
+pub use protocol::{respond, ClientView, Request, RequestLine, Response, ResponseLine};
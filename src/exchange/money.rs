@@ -0,0 +1,223 @@
+use crate::ExchangeError;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Digits after the decimal point of every price and balance. Journals,
+/// snapshots and the protocol carry decimal strings, so they stay valid
+/// when this changes as long as no value needs more digits.
+pub const DECIMALS: u32 = 4;
+
+type Raw = u64;
+
+const SCALE: Raw = (10 as Raw).pow(DECIMALS);
+
+/// Fixed-point non-negative decimal with `DECIMALS` digits after the
+/// point, stored as a whole number of the smallest unit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Price(Raw);
+
+/// Balances use the same fixed-point representation as prices.
+pub type Money = Price;
+
+impl Price {
+    pub const ZERO: Price = Price(0);
+
+    /// Whole units, e.g. `Price::units(10)` is 10.
+    pub const fn units(units: u32) -> Self {
+        Price(units as Raw * SCALE)
+    }
+
+    /// Smallest units, e.g. with 4 decimals `Price::from_minor(25)` is 0.0025.
+    pub const fn from_minor(minor: Raw) -> Self {
+        Price(minor)
+    }
+
+    pub fn minor(self) -> Raw {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Price) -> Option<Price> {
+        self.0.checked_add(other.0).map(Price)
+    }
+
+    pub fn checked_sub(self, other: Price) -> Option<Price> {
+        self.0.checked_sub(other.0).map(Price)
+    }
+
+    pub fn saturating_sub(self, other: Price) -> Price {
+        Price(self.0.saturating_sub(other.0))
+    }
+
+    /// Cost of `amount` units at this price.
+    pub fn checked_mul(self, amount: u32) -> Option<Money> {
+        self.0.checked_mul(amount as Raw).map(Price)
+    }
+
+    /// How many whole units at `price` this much money pays for.
+    pub fn units_at(self, price: Price) -> u32 {
+        match self.0.checked_div(price.0) {
+            Some(units) => units.min(u32::MAX as Raw) as u32,
+            None => u32::MAX,
+        }
+    }
+
+    /// Whether this is a whole number of `step`s. A zero step allows
+    /// anything.
+    pub fn is_multiple_of(self, step: Price) -> bool {
+        step.0 == 0 || self.0.is_multiple_of(step.0)
+    }
+
+    /// The value as a whole number, when it has no fractional part.
+    pub fn whole_units(self) -> Option<u32> {
+        if !self.0.is_multiple_of(SCALE) {
+            return None;
+        }
+        u32::try_from(self.0 / SCALE).ok()
+    }
+}
+
+impl From<u32> for Price {
+    fn from(units: u32) -> Self {
+        Price::units(units)
+    }
+}
+
+impl FromStr for Price {
+    type Err = ExchangeError;
+
+    /// `12`, `12.5` or `12.3456`, at most `DECIMALS` digits after the point.
+    fn from_str(input: &str) -> Result<Price, ExchangeError> {
+        let (whole, fraction) = match input.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (input, ""),
+        };
+        let digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if whole.is_empty() || !digits(whole) || !digits(fraction) {
+            return Err(ExchangeError::ProblemWithNumber);
+        }
+        if fraction.len() > DECIMALS as usize || (input.contains('.') && fraction.is_empty()) {
+            return Err(ExchangeError::ProblemWithNumber);
+        }
+        let whole = whole
+            .parse::<Raw>()
+            .map_err(|_| ExchangeError::ProblemWithNumber)?;
+        let mut minor = 0;
+        if !fraction.is_empty() {
+            let padding = (10 as Raw).pow(DECIMALS - fraction.len() as u32);
+            minor = fraction
+                .parse::<Raw>()
+                .map_err(|_| ExchangeError::ProblemWithNumber)?
+                * padding;
+        }
+        whole
+            .checked_mul(SCALE)
+            .and_then(|whole| whole.checked_add(minor))
+            .map(Price)
+            .ok_or(ExchangeError::ProblemWithNumber)
+    }
+}
+
+/// Shortest form: `10`, `10.5`, `0.0025`.
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole = self.0 / SCALE;
+        let fraction = self.0 % SCALE;
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+        let fraction = format!("{:0width$}", fraction, width = DECIMALS as usize);
+        write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl Serialize for Price {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Accepts decimal strings and plain JSON numbers.
+impl<'de> Deserialize<'de> for Price {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Price, D::Error> {
+        struct PriceVisitor;
+
+        impl Visitor<'_> for PriceVisitor {
+            type Value = Price;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a non-negative decimal")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Price, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Price, E> {
+                value
+                    .checked_mul(SCALE)
+                    .map(Price)
+                    .ok_or_else(|| E::custom(ExchangeError::ProblemWithNumber))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Price, E> {
+                let value = u64::try_from(value)
+                    .map_err(|_| E::custom(ExchangeError::ProblemWithNumber))?;
+                self.visit_u64(value)
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Price, E> {
+                self.visit_str(&value.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(PriceVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("12".parse(), Ok(Price::units(12)));
+        assert_eq!("12.5".parse::<Price>().unwrap().minor(), 125_000);
+        assert_eq!("0.0025".parse(), Ok(Price::from_minor(25)));
+        for bad in ["", ".5", "5.", "1.23456", "-1", "1e3", "1.2.3"] {
+            assert_eq!(bad.parse::<Price>(), Err(ExchangeError::ProblemWithNumber));
+        }
+        assert_eq!(Price::units(3877).to_string(), "3877");
+        assert_eq!("10.50".parse::<Price>().unwrap().to_string(), "10.5");
+        assert_eq!(Price::from_minor(25).to_string(), "0.0025");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let price: Price = "2.5".parse().unwrap();
+        assert_eq!(price.checked_mul(3), Some("7.5".parse().unwrap()));
+        assert_eq!(Price::from_minor(u64::MAX).checked_mul(2), None);
+        assert_eq!(Price::units(10).units_at(price), 4);
+        assert_eq!(Price::units(10).units_at(Price::ZERO), u32::MAX);
+        assert!(Price::units(10).is_multiple_of(price));
+        assert!(!"10.1".parse::<Price>().unwrap().is_multiple_of(price));
+        assert_eq!(Price::units(7).whole_units(), Some(7));
+        assert_eq!(price.whole_units(), None);
+        assert_eq!(Price::units(1).checked_sub(price), None);
+    }
+
+    #[test]
+    fn test_serde() {
+        let price: Price = "10.25".parse().unwrap();
+        assert_eq!(serde_json::to_string(&price).unwrap(), "\"10.25\"");
+        assert_eq!(serde_json::from_str::<Price>("\"10.25\"").unwrap(), price);
+        assert_eq!(serde_json::from_str::<Price>("10.25").unwrap(), price);
+        assert_eq!(serde_json::from_str::<Price>("7").unwrap(), Price::units(7));
+        assert!(serde_json::from_str::<Price>("-7").is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use crate::{
+    auction_price, AuctionPrice, CircuitBreaker, Operation, Order, OrderId, Price, PriceBand,
+    TradingStatus, TriggerBook,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Aggregated view of one price level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PriceLevel {
+    pub price: Price,
+    pub amount: u32,
+    pub orders: usize,
+}
+
+/// Best price levels of both sides, best first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Depth {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+/// Resting orders of one ticker. Price levels are kept sorted so the best
+/// bid is the last key of `bids` and the best ask is the first key of
+/// `asks`; each level is a FIFO queue.
+#[derive(Debug, Clone)]
+pub struct OrderBook {
+    bids: BTreeMap<Price, VecDeque<Order>>,
+    asks: BTreeMap<Price, VecDeque<Order>>,
+    index: HashMap<OrderId, (Operation, Price)>,
+    /// Limit prices must be a multiple of it.
+    tick_size: Price,
+    /// Order amounts must be a multiple of it.
+    lot_size: u32,
+    /// Stop orders waiting for `last_price` to reach them.
+    triggers: TriggerBook,
+    last_price: Option<Price>,
+    status: TradingStatus,
+    /// Limit prices outside it are rejected and nothing trades outside it.
+    price_band: Option<PriceBand>,
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Orders taken off since `take_departed`.
+    removed: Vec<OrderId>,
+}
+
+impl Default for OrderBook {
+    /// Any price with `DECIMALS` digits and any amount are accepted.
+    fn default() -> Self {
+        OrderBook {
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            index: HashMap::new(),
+            tick_size: Price::from_minor(1),
+            lot_size: 1,
+            triggers: TriggerBook::new(),
+            last_price: None,
+            status: TradingStatus::default(),
+            price_band: None,
+            circuit_breaker: None,
+            removed: Vec::new(),
+        }
+    }
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        OrderBook::default()
+    }
+
+    pub fn get_tick_size(&self) -> Price {
+        self.tick_size
+    }
+
+    pub fn set_tick_size(&mut self, tick_size: Price) {
+        self.tick_size = tick_size;
+    }
+
+    pub fn get_lot_size(&self) -> u32 {
+        self.lot_size
+    }
+
+    pub fn set_lot_size(&mut self, lot_size: u32) {
+        self.lot_size = lot_size;
+    }
+
+    pub fn get_triggers(&self) -> &TriggerBook {
+        &self.triggers
+    }
+
+    pub fn get_triggers_mut(&mut self) -> &mut TriggerBook {
+        &mut self.triggers
+    }
+
+    /// Price of the last trade on this ticker.
+    pub fn get_last_price(&self) -> Option<Price> {
+        self.last_price
+    }
+
+    pub fn set_last_price(&mut self, last_price: Option<Price>) {
+        self.last_price = last_price;
+    }
+
+    /// Records a trade at `price`. Returns true when the trade tripped
+    /// the circuit breaker, which halts the ticker.
+    pub fn record_trade(&mut self, price: Price) -> bool {
+        self.last_price = Some(price);
+        let tripped = self
+            .circuit_breaker
+            .as_mut()
+            .is_some_and(|breaker| breaker.record(price));
+        if tripped {
+            self.status = TradingStatus::Halted;
+        }
+        tripped
+    }
+
+    pub fn get_status(&self) -> TradingStatus {
+        self.status
+    }
+
+    /// Opening the ticker again makes the circuit breaker forget the
+    /// trades before.
+    pub fn set_status(&mut self, status: TradingStatus) {
+        if status == TradingStatus::Open {
+            if let Some(breaker) = self.circuit_breaker.as_mut() {
+                breaker.reset();
+            }
+        }
+        self.status = status;
+    }
+
+    pub fn get_price_band(&self) -> Option<PriceBand> {
+        self.price_band
+    }
+
+    pub fn set_price_band(&mut self, price_band: Option<PriceBand>) {
+        self.price_band = price_band;
+    }
+
+    /// Whether `price` is inside the price band, always true without one.
+    pub fn within_band(&self, price: Price) -> bool {
+        self.price_band.is_none_or(|band| band.contains(price))
+    }
+
+    pub fn get_circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub fn set_circuit_breaker(&mut self, circuit_breaker: Option<CircuitBreaker>) {
+        self.circuit_breaker = circuit_breaker;
+    }
+
+    pub fn best_bid(&self) -> Option<Price> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<Price> {
+        self.asks.keys().next().copied()
+    }
+
+    pub fn best_price(&self, operation: Operation) -> Option<Price> {
+        match operation {
+            Operation::Buy => self.best_bid(),
+            Operation::Sell => self.best_ask(),
+        }
+    }
+
+    /// Best ask minus best bid, when both sides have orders.
+    pub fn spread(&self) -> Option<Price> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => Some(ask.saturating_sub(bid)),
+            _ => None,
+        }
+    }
+
+    pub fn depth(&self, levels: usize) -> Depth {
+        let level = |(price, orders): (&Price, &VecDeque<Order>)| PriceLevel {
+            price: *price,
+            amount: orders.iter().map(Order::get_visible_amount).sum(),
+            orders: orders.len(),
+        };
+        Depth {
+            bids: self.bids.iter().rev().take(levels).map(level).collect(),
+            asks: self.asks.iter().take(levels).map(level).collect(),
+        }
+    }
+
+    /// Where an auction on this book would uncross now, hidden iceberg
+    /// amounts included. Only prices inside the price band are candidates.
+    /// The last trade price, or else the band's reference price, breaks
+    /// ties.
+    pub fn auction_price(&self) -> Option<AuctionPrice> {
+        let level = |(price, orders): (&Price, &VecDeque<Order>)| {
+            let amount = orders.iter().map(|order| order.get_amount() as u64).sum();
+            (*price, amount)
+        };
+        let bids: Vec<(Price, u64)> = self.bids.iter().rev().map(level).collect();
+        let asks: Vec<(Price, u64)> = self.asks.iter().map(level).collect();
+        let reference_price = self
+            .last_price
+            .or(self.price_band.map(|band| band.reference_price));
+        auction_price(&bids, &asks, self.price_band, reference_price)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn has_level(&self, operation: Operation, price: Price) -> bool {
+        self.side(operation).contains_key(&price)
+    }
+
+    pub fn contains(&self, order_id: OrderId) -> bool {
+        self.index.contains_key(&order_id)
+    }
+
+    pub fn get(&self, order_id: OrderId) -> Option<&Order> {
+        let (operation, price) = self.index.get(&order_id)?;
+        self.side(*operation)
+            .get(price)?
+            .iter()
+            .find(|order| order.get_id() == order_id)
+    }
+
+    pub fn get_mut(&mut self, order_id: OrderId) -> Option<&mut Order> {
+        let (operation, price) = *self.index.get(&order_id)?;
+        self.side_mut(operation)
+            .get_mut(&price)?
+            .iter_mut()
+            .find(|order| order.get_id() == order_id)
+    }
+
+    /// Puts the order at the back of its price level.
+    pub fn insert(&mut self, order: Order) {
+        self.index
+            .insert(order.get_id(), (order.get_operation(), order.get_price()));
+        self.side_mut(order.get_operation())
+            .entry(order.get_price())
+            .or_default()
+            .push_back(order);
+    }
+
+    pub fn remove(&mut self, order_id: OrderId) -> Option<Order> {
+        let (operation, price) = self.index.remove(&order_id)?;
+        self.removed.push(order_id);
+        let side = self.side_mut(operation);
+        let orders = side.get_mut(&price)?;
+        let position = orders.iter().position(|order| order.get_id() == order_id)?;
+        let order = orders.remove(position);
+        if orders.is_empty() {
+            side.remove(&price);
+        }
+        order
+    }
+
+    /// The oldest order at the best price of the given side.
+    pub fn front_mut(&mut self, operation: Operation) -> Option<&mut Order> {
+        let orders = match operation {
+            Operation::Buy => self.bids.values_mut().next_back()?,
+            Operation::Sell => self.asks.values_mut().next()?,
+        };
+        orders.front_mut()
+    }
+
+    /// Moves the oldest order at the best price of the given side to the
+    /// back of its level.
+    pub fn requeue_front(&mut self, operation: Operation) {
+        let orders = match operation {
+            Operation::Buy => self.bids.values_mut().next_back(),
+            Operation::Sell => self.asks.values_mut().next(),
+        };
+        if let Some(orders) = orders {
+            if let Some(order) = orders.pop_front() {
+                orders.push_back(order);
+            }
+        }
+    }
+
+    pub fn pop_front(&mut self, operation: Operation) -> Option<Order> {
+        let price = self.best_price(operation)?;
+        let side = self.side_mut(operation);
+        let orders = side.get_mut(&price)?;
+        let order = orders.pop_front();
+        if orders.is_empty() {
+            side.remove(&price);
+        }
+        if let Some(order) = &order {
+            self.index.remove(&order.get_id());
+            self.removed.push(order.get_id());
+        }
+        order
+    }
+
+    /// Ids of the orders that left the book, stop orders included, since
+    /// the last call. An order that came back, e.g. a modified order or an
+    /// activated stop order now resting, has not left.
+    pub fn take_departed(&mut self) -> Vec<OrderId> {
+        let mut departed = std::mem::take(&mut self.removed);
+        departed.extend(self.triggers.take_removed());
+        departed.retain(|order_id| !self.contains(*order_id) && !self.triggers.contains(*order_id));
+        departed
+    }
+
+    /// Orders of one side from the best price outwards, FIFO within a level.
+    pub fn orders(&self, operation: Operation) -> Box<dyn Iterator<Item = &Order> + '_> {
+        match operation {
+            Operation::Buy => Box::new(self.bids.values().rev().flatten()),
+            Operation::Sell => Box::new(self.asks.values().flatten()),
+        }
+    }
+
+    fn side(&self, operation: Operation) -> &BTreeMap<Price, VecDeque<Order>> {
+        match operation {
+            Operation::Buy => &self.bids,
+            Operation::Sell => &self.asks,
+        }
+    }
+
+    fn side_mut(&mut self, operation: Operation) -> &mut BTreeMap<Price, VecDeque<Order>> {
+        match operation {
+            Operation::Buy => &mut self.bids,
+            Operation::Sell => &mut self.asks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: OrderId, operation: Operation, price: u32, amount: u32) -> Order {
+        let mut order = Order::new("C", operation, "A", Price::from(price), amount);
+        order.set_id(id);
+        order
+    }
+
+    #[test]
+    fn test_best_prices() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(book.best_ask(), None);
+        book.insert(order(1, Operation::Buy, 10, 1));
+        book.insert(order(2, Operation::Buy, 12, 1));
+        book.insert(order(3, Operation::Sell, 15, 1));
+        book.insert(order(4, Operation::Sell, 14, 1));
+        assert_eq!(book.best_bid(), Some(12.into()));
+        assert_eq!(book.best_ask(), Some(14.into()));
+        assert_eq!(book.len(), 4);
+    }
+
+    #[test]
+    fn test_fifo_within_level() {
+        let mut book = OrderBook::new();
+        book.insert(order(1, Operation::Sell, 10, 1));
+        book.insert(order(2, Operation::Sell, 10, 2));
+        book.insert(order(3, Operation::Sell, 11, 3));
+        assert_eq!(book.front_mut(Operation::Sell).unwrap().get_id(), 1);
+        assert_eq!(book.pop_front(Operation::Sell).unwrap().get_id(), 1);
+        assert_eq!(book.pop_front(Operation::Sell).unwrap().get_id(), 2);
+        assert_eq!(book.best_ask(), Some(11.into()));
+        assert!(!book.has_level(Operation::Sell, 10.into()));
+        assert!(book.has_level(Operation::Sell, 11.into()));
+        assert!(!book.contains(2));
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn test_requeue_and_visible_depth() {
+        let mut book = OrderBook::new();
+        let mut iceberg = order(1, Operation::Sell, 10, 12);
+        iceberg.set_display_amount(Some(5));
+        iceberg.refresh();
+        book.insert(iceberg);
+        book.insert(order(2, Operation::Sell, 10, 3));
+        assert_eq!(book.depth(1).asks[0].amount, 8);
+        book.requeue_front(Operation::Sell);
+        let ids: Vec<OrderId> = book.orders(Operation::Sell).map(Order::get_id).collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert!(book.contains(1));
+    }
+
+    #[test]
+    fn test_remove_and_get() {
+        let mut book = OrderBook::new();
+        book.insert(order(1, Operation::Buy, 10, 1));
+        book.insert(order(2, Operation::Buy, 10, 2));
+        book.get_mut(2).unwrap().set_amount(5);
+        assert_eq!(book.get(2).unwrap().get_amount(), 5);
+        assert_eq!(book.remove(1).unwrap().get_id(), 1);
+        assert_eq!(book.remove(1), None);
+        assert_eq!(book.remove(2).unwrap().get_amount(), 5);
+        assert!(book.is_empty());
+        assert_eq!(book.best_bid(), None);
+    }
+
+    #[test]
+    fn test_spread_and_depth() {
+        let mut book = OrderBook::new();
+        book.insert(order(1, Operation::Buy, 10, 1));
+        assert_eq!(book.spread(), None);
+        book.insert(order(2, Operation::Buy, 10, 2));
+        book.insert(order(3, Operation::Buy, 9, 4));
+        book.insert(order(4, Operation::Buy, 8, 1));
+        book.insert(order(5, Operation::Sell, 13, 3));
+        assert_eq!(book.spread(), Some(3.into()));
+        let depth = book.depth(2);
+        assert_eq!(
+            depth.bids,
+            vec![
+                PriceLevel {
+                    price: 10.into(),
+                    amount: 3,
+                    orders: 2
+                },
+                PriceLevel {
+                    price: 9.into(),
+                    amount: 4,
+                    orders: 1
+                },
+            ]
+        );
+        assert_eq!(
+            depth.asks,
+            vec![PriceLevel {
+                price: 13.into(),
+                amount: 3,
+                orders: 1
+            }]
+        );
+        assert_eq!(OrderBook::new().depth(5), Depth::default());
+    }
+
+    #[test]
+    fn test_orders_iteration() {
+        let mut book = OrderBook::new();
+        book.insert(order(1, Operation::Buy, 10, 1));
+        book.insert(order(2, Operation::Buy, 12, 1));
+        book.insert(order(3, Operation::Buy, 10, 1));
+        let ids: Vec<OrderId> = book.orders(Operation::Buy).map(Order::get_id).collect();
+        assert_eq!(ids, vec![2, 1, 3]);
+        assert_eq!(book.orders(Operation::Sell).count(), 0);
+    }
+}
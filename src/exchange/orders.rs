@@ -1,7 +1,12 @@
+use crate::{ExchangeError, Price, Result};
+use serde::{Deserialize, Serialize};
 use std::ops::Not;
 use std::str::FromStr;
 
-#[derive(PartialEq, Clone, Copy, Debug, Eq, Hash)]
+pub type OrderId = u64;
+
+#[derive(PartialEq, Clone, Copy, Debug, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Operation {
     Buy,
     Sell,
@@ -30,26 +35,172 @@ impl FromStr for Operation {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(PartialEq, Clone, Copy, Debug, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    #[default]
+    Limit,
+    /// Trades at any price against the book and never rests. A market buy
+    /// is capped by the money the client has available.
+    Market,
+    /// Waits off the book until a trade reaches the stop price, then
+    /// becomes a market order.
+    Stop,
+    /// Waits off the book until a trade reaches the stop price, then
+    /// becomes a limit order.
+    StopLimit,
+}
+
+impl OrderType {
+    /// Whether the order carries a limit price.
+    pub fn has_limit(self) -> bool {
+        matches!(self, OrderType::Limit | OrderType::StopLimit)
+    }
+
+    pub fn is_stop(self) -> bool {
+        matches!(self, OrderType::Stop | OrderType::StopLimit)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    /// Good till cancelled.
+    #[default]
+    Gtc,
+    /// Immediate or cancel: whatever does not trade at once is dropped.
+    Ioc,
+    /// Fill or kill: trades in full at once or not at all.
+    Fok,
+    /// Rests until `ExchangeOperation::end_of_day`.
+    Day,
+}
+
+/// What happens when an incoming order would trade with a resting order
+/// of the same client. Set on the incoming order.
+#[derive(PartialEq, Clone, Copy, Debug, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTradePrevention {
+    /// The incoming order is cancelled, the resting one stays.
+    #[default]
+    CancelNewest,
+    /// The resting order is cancelled and matching goes on.
+    CancelOldest,
+    CancelBoth,
+    /// Both orders are reduced by the smaller amount, whichever
+    /// reaches zero is cancelled.
+    DecrementAndCancel,
+}
+
+/// Post-only orders never take liquidity, they only rest. Set on the
+/// incoming order.
+#[derive(PartialEq, Clone, Copy, Debug, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostOnly {
+    /// The order may trade on arrival.
+    #[default]
+    Off,
+    /// An order that would trade on arrival is rejected.
+    Reject,
+    /// An order that would trade on arrival is repriced one tick behind
+    /// the best opposite price.
+    Reprice,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Order {
+    id: OrderId,
     client: String,
     operation: Operation,
     ticker: String,
-    price: u32,
+    price: Price,
     amount: u32,
+    order_type: OrderType,
+    /// Last trade price that activates a stop order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stop_price: Option<Price>,
+    /// Size of the slice an iceberg order shows while resting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_amount: Option<u32>,
+    /// What is left of the slice an iceberg order shows now.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    visible_amount: u32,
+    time_in_force: TimeInForce,
+    self_trade_prevention: SelfTradePrevention,
+    #[serde(default)]
+    post_only: PostOnly,
 }
 
 impl Order {
-    pub fn new(client: &str, operation: Operation, ticker: &str, price: u32, amount: u32) -> Self {
+    pub fn new(
+        client: &str,
+        operation: Operation,
+        ticker: &str,
+        price: Price,
+        amount: u32,
+    ) -> Self {
         Order {
+            id: 0,
             client: client.to_string(),
             ticker: ticker.to_string(),
             price,
             amount,
             operation,
+            order_type: OrderType::default(),
+            stop_price: None,
+            display_amount: None,
+            visible_amount: 0,
+            time_in_force: TimeInForce::default(),
+            self_trade_prevention: SelfTradePrevention::default(),
+            post_only: PostOnly::default(),
         }
     }
 
+    /// Market order, it carries no price.
+    pub fn new_market(client: &str, operation: Operation, ticker: &str, amount: u32) -> Self {
+        let mut order = Order::new(client, operation, ticker, Price::ZERO, amount);
+        order.order_type = OrderType::Market;
+        order
+    }
+
+    /// Market order activated once a trade reaches `stop_price`: at or
+    /// above it for a buy, at or below it for a sell.
+    pub fn new_stop(
+        client: &str,
+        operation: Operation,
+        ticker: &str,
+        stop_price: Price,
+        amount: u32,
+    ) -> Self {
+        let mut order = Order::new_market(client, operation, ticker, amount);
+        order.order_type = OrderType::Stop;
+        order.stop_price = Some(stop_price);
+        order
+    }
+
+    /// Limit order at `price` activated once a trade reaches `stop_price`.
+    pub fn new_stop_limit(
+        client: &str,
+        operation: Operation,
+        ticker: &str,
+        stop_price: Price,
+        price: Price,
+        amount: u32,
+    ) -> Self {
+        let mut order = Order::new(client, operation, ticker, price, amount);
+        order.order_type = OrderType::StopLimit;
+        order.stop_price = Some(stop_price);
+        order
+    }
+
+    pub fn get_id(&self) -> OrderId {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: OrderId) {
+        self.id = id;
+    }
+
     pub fn get_client(&self) -> &str {
         &self.client
     }
@@ -58,7 +209,7 @@ impl Order {
         &self.ticker
     }
 
-    pub fn get_price(&self) -> u32 {
+    pub fn get_price(&self) -> Price {
         self.price
     }
 
@@ -70,8 +221,106 @@ impl Order {
         self.operation
     }
 
-    pub fn sub_amount(&mut self, new_amount: u32) {
-        self.amount = self.amount.checked_sub(new_amount).unwrap(); // because we pick minimum, where we use it
+    pub fn set_price(&mut self, price: Price) {
+        self.price = price;
+    }
+
+    pub fn set_amount(&mut self, amount: u32) {
+        self.amount = amount;
+        self.visible_amount = self.visible_amount.min(amount);
+    }
+
+    pub fn get_display_amount(&self) -> Option<u32> {
+        self.display_amount
+    }
+
+    /// Makes the order an iceberg that shows at most `display_amount` of
+    /// its amount while resting, `None` shows all of it.
+    pub fn set_display_amount(&mut self, display_amount: Option<u32>) {
+        self.display_amount = display_amount;
+    }
+
+    /// What the book shows and what resting orders trade before an
+    /// iceberg has to refresh its slice.
+    pub fn get_visible_amount(&self) -> u32 {
+        match self.display_amount {
+            Some(_) => self.visible_amount,
+            None => self.amount,
+        }
+    }
+
+    /// Shows the next slice of an iceberg out of its hidden reserve.
+    pub fn refresh(&mut self) {
+        if let Some(display_amount) = self.display_amount {
+            self.visible_amount = display_amount.min(self.amount);
+        }
+    }
+
+    pub fn get_order_type(&self) -> OrderType {
+        self.order_type
+    }
+
+    pub fn get_stop_price(&self) -> Option<Price> {
+        self.stop_price
+    }
+
+    /// Whether a trade at `last_price` activates this stop order.
+    pub fn is_triggered_by(&self, last_price: Price) -> bool {
+        match (self.stop_price, self.operation) {
+            (Some(stop_price), Operation::Buy) => last_price >= stop_price,
+            (Some(stop_price), Operation::Sell) => last_price <= stop_price,
+            (None, _) => false,
+        }
+    }
+
+    /// Turns an activated stop order into the market or limit order it
+    /// stood for.
+    pub fn trigger(&mut self) {
+        self.order_type = match self.order_type {
+            OrderType::Stop => OrderType::Market,
+            OrderType::StopLimit => OrderType::Limit,
+            order_type => order_type,
+        };
+    }
+
+    pub fn get_time_in_force(&self) -> TimeInForce {
+        self.time_in_force
+    }
+
+    pub fn set_time_in_force(&mut self, time_in_force: TimeInForce) {
+        self.time_in_force = time_in_force;
+    }
+
+    /// Whether what is left of the order may rest on the book.
+    pub fn can_rest(&self) -> bool {
+        self.order_type == OrderType::Limit
+            && matches!(self.time_in_force, TimeInForce::Gtc | TimeInForce::Day)
+    }
+
+    pub fn get_self_trade_prevention(&self) -> SelfTradePrevention {
+        self.self_trade_prevention
+    }
+
+    pub fn set_self_trade_prevention(&mut self, self_trade_prevention: SelfTradePrevention) {
+        self.self_trade_prevention = self_trade_prevention;
+    }
+
+    pub fn get_post_only(&self) -> PostOnly {
+        self.post_only
+    }
+
+    pub fn set_post_only(&mut self, post_only: PostOnly) {
+        self.post_only = post_only;
+    }
+
+    /// Takes `new_amount` off the order, out of the visible slice first.
+    pub fn sub_amount(&mut self, new_amount: u32) -> Result<()> {
+        self.amount = self
+            .amount
+            .checked_sub(new_amount)
+            .ok_or(ExchangeError::SubtractionOverflow)?;
+        self.visible_amount = self.visible_amount.saturating_sub(new_amount);
+        Ok(())
     }
 
     pub fn compare_for_tx(&self, rhs: &Self) -> bool {
@@ -82,57 +331,145 @@ impl Order {
     }
 }
 
+fn is_zero(amount: &u32) -> bool {
+    *amount == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_creation() {
-        let order = Order::new("C1", Operation::Buy, "A", 10, 20);
+        let order = Order::new("C1", Operation::Buy, "A", 10.into(), 20);
         assert_eq!(order.get_client(), "C1");
         assert_eq!(order.get_ticker(), "A");
-        assert_eq!(order.get_price(), 10);
+        assert_eq!(order.get_price(), 10.into());
         assert_eq!(order.get_amount(), 20);
+        assert_eq!(order.get_id(), 0);
+        assert_eq!(order.get_order_type(), OrderType::Limit);
+        assert_eq!(order.get_time_in_force(), TimeInForce::Gtc);
+        assert!(order.can_rest());
+    }
+
+    #[test]
+    fn test_market_and_time_in_force() {
+        let order = Order::new_market("C1", Operation::Sell, "A", 20);
+        assert_eq!(order.get_order_type(), OrderType::Market);
+        assert_eq!(order.get_price(), 0.into());
+        assert!(!order.can_rest());
+
+        let mut order = Order::new("C1", Operation::Buy, "A", 10.into(), 20);
+        order.set_time_in_force(TimeInForce::Day);
+        assert!(order.can_rest());
+        order.set_time_in_force(TimeInForce::Ioc);
+        assert!(!order.can_rest());
+        order.set_time_in_force(TimeInForce::Fok);
+        assert!(!order.can_rest());
+    }
+
+    #[test]
+    fn test_stop_orders() {
+        let mut order = Order::new_stop("C1", Operation::Buy, "A", 12.into(), 5);
+        assert_eq!(order.get_order_type(), OrderType::Stop);
+        assert_eq!(order.get_stop_price(), Some(12.into()));
+        assert!(!order.can_rest());
+        assert!(!order.is_triggered_by(11.into()));
+        assert!(order.is_triggered_by(12.into()));
+        order.trigger();
+        assert_eq!(order.get_order_type(), OrderType::Market);
+
+        let mut order = Order::new_stop_limit("C1", Operation::Sell, "A", 9.into(), 8.into(), 5);
+        assert!(order.is_triggered_by(9.into()));
+        assert!(!order.is_triggered_by(10.into()));
+        order.trigger();
+        assert_eq!(order.get_order_type(), OrderType::Limit);
+        assert_eq!(order.get_price(), 8.into());
+        assert!(order.can_rest());
+        assert!(!Order::new("C1", Operation::Buy, "A", 1.into(), 1).is_triggered_by(1.into()));
+    }
+
+    #[test]
+    fn test_iceberg_slices() {
+        let mut order = Order::new("C1", Operation::Sell, "A", 10.into(), 12);
+        assert_eq!(order.get_visible_amount(), 12);
+        order.set_display_amount(Some(5));
+        assert_eq!(order.get_visible_amount(), 0);
+        order.refresh();
+        assert_eq!(order.get_visible_amount(), 5);
+        order.sub_amount(3).unwrap();
+        assert_eq!((order.get_amount(), order.get_visible_amount()), (9, 2));
+        order.sub_amount(2).unwrap();
+        assert_eq!(order.get_visible_amount(), 0);
+        order.refresh();
+        assert_eq!(order.get_visible_amount(), 5);
+        order.set_amount(4);
+        assert_eq!(order.get_visible_amount(), 4);
+        order.refresh();
+        assert_eq!(order.get_visible_amount(), 4);
+    }
+
+    #[test]
+    fn test_set_id_price_amount() {
+        let mut order = Order::new("C1", Operation::Buy, "A", 10.into(), 20);
+        order.set_id(7);
+        order.set_price(12.into());
+        order.set_amount(5);
+        assert_eq!(order.get_id(), 7);
+        assert_eq!(order.get_price(), 12.into());
+        assert_eq!(order.get_amount(), 5);
     }
 
     #[test]
     fn test_compare() {
-        let order = Order::new("C1", Operation::Buy, "A", 10, 20);
+        let order = Order::new("C1", Operation::Buy, "A", 10.into(), 20);
         assert_eq!(order.get_client(), "C1");
         assert_eq!(order.get_ticker(), "A");
-        assert_eq!(order.get_price(), 10);
+        assert_eq!(order.get_price(), 10.into());
         assert_eq!(order.get_amount(), 20);
 
-        let new_order = Order::new("C2", Operation::Sell, "A", 10, 20);
+        let new_order = Order::new("C2", Operation::Sell, "A", 10.into(), 20);
         assert_eq!(new_order.get_client(), "C2");
         assert_eq!(new_order.get_ticker(), "A");
-        assert_eq!(new_order.get_price(), 10);
+        assert_eq!(new_order.get_price(), 10.into());
         assert_eq!(new_order.get_amount(), 20);
 
         assert!(order.compare_for_tx(&new_order));
 
-        let new_order = Order::new("C2", Operation::Sell, "B", 10, 20);
+        let new_order = Order::new("C2", Operation::Sell, "B", 10.into(), 20);
         assert_eq!(new_order.get_client(), "C2");
         assert_eq!(new_order.get_ticker(), "B");
-        assert_eq!(new_order.get_price(), 10);
+        assert_eq!(new_order.get_price(), 10.into());
         assert_eq!(new_order.get_amount(), 20);
 
         assert!(!order.compare_for_tx(&new_order));
 
-        let new_order = Order::new("C2", Operation::Sell, "A", 20, 20);
+        let new_order = Order::new("C2", Operation::Sell, "A", 20.into(), 20);
         assert_eq!(new_order.get_client(), "C2");
         assert_eq!(new_order.get_ticker(), "A");
-        assert_eq!(new_order.get_price(), 20);
+        assert_eq!(new_order.get_price(), 20.into());
         assert_eq!(new_order.get_amount(), 20);
 
         assert!(!order.compare_for_tx(&new_order));
 
-        let new_order = Order::new("C2", Operation::Sell, "A", 10, 10);
+        let new_order = Order::new("C2", Operation::Sell, "A", 10.into(), 10);
         assert_eq!(new_order.get_client(), "C2");
         assert_eq!(new_order.get_ticker(), "A");
-        assert_eq!(new_order.get_price(), 10);
+        assert_eq!(new_order.get_price(), 10.into());
         assert_eq!(new_order.get_amount(), 10);
 
         assert!(!order.compare_for_tx(&new_order));
     }
+
+    #[test]
+    fn test_sub_amount() {
+        let mut order = Order::new("C1", Operation::Buy, "A", 10.into(), 20);
+        assert_eq!(order.sub_amount(5), Ok(()));
+        assert_eq!(order.get_amount(), 15);
+        assert_eq!(
+            order.sub_amount(16),
+            Err(ExchangeError::SubtractionOverflow)
+        );
+        assert_eq!(order.get_amount(), 15);
+    }
 }
@@ -0,0 +1,676 @@
+use crate::{
+    AuctionPrice, AuctionReport, CircuitBreaker, Client, ClientsDb, Depth, ExchangeError,
+    ExchangeOperation, ExecutionReport, Fill, JournalDb, Money, Operation, Order, OrderId,
+    OrderType, OrdersDb, PostOnly, Price, PriceBand, Result, SelfTradePrevention, TimeInForce,
+    TradingStatus,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// One request of the JSON Lines protocol, e.g.
+/// `{"id":1,"type":"place_order","client":"C1","side":"buy","ticker":"A","price":10,"amount":5}`.
+/// The optional `id` is echoed back on the response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestLine {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// Lists a ticker, orders and holdings of unlisted tickers are
+    /// rejected.
+    AddTicker {
+        ticker: String,
+    },
+    /// Registers a client holding stocks of listed tickers.
+    AddClient {
+        name: String,
+        #[serde(default)]
+        balance: Money,
+        #[serde(default)]
+        stocks: BTreeMap<String, u32>,
+    },
+    /// Sets the tick size and lot size of a listed ticker.
+    SetTickerRules {
+        ticker: String,
+        #[serde(default = "default_tick_size")]
+        tick_size: Price,
+        #[serde(default = "default_lot_size")]
+        lot_size: u32,
+    },
+    /// Opens, halts or closes a ticker, `open` also resumes a ticker the
+    /// circuit breaker halted and `auction` starts an auction call.
+    SetTradingStatus {
+        ticker: String,
+        status: TradingStatus,
+    },
+    /// Uncrosses the auction call and moves the ticker to `status`.
+    EndAuction {
+        ticker: String,
+        #[serde(default)]
+        status: TradingStatus,
+    },
+    /// Without `reference_price` and `width_bps` the band is removed.
+    SetPriceBand {
+        ticker: String,
+        #[serde(flatten)]
+        price_band: Option<PriceBand>,
+    },
+    /// Without `move_bps` and `window` the breaker is removed.
+    SetCircuitBreaker {
+        ticker: String,
+        #[serde(flatten)]
+        circuit_breaker: Option<CircuitBreaker>,
+    },
+    /// Money, or whole stocks when `ticker` is given.
+    Deposit {
+        client: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ticker: Option<String>,
+        amount: Money,
+    },
+    Withdraw {
+        client: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ticker: Option<String>,
+        amount: Money,
+    },
+    PlaceOrder {
+        client: String,
+        side: Operation,
+        ticker: String,
+        /// Ignored for market and stop orders.
+        #[serde(default)]
+        price: Price,
+        amount: u32,
+        #[serde(default)]
+        order_type: OrderType,
+        /// Only for stop and stop-limit orders.
+        #[serde(default)]
+        stop_price: Price,
+        /// Makes a limit order an iceberg showing this much at a time.
+        #[serde(default)]
+        display_amount: Option<u32>,
+        #[serde(default)]
+        time_in_force: TimeInForce,
+        #[serde(default)]
+        self_trade_prevention: SelfTradePrevention,
+        #[serde(default)]
+        post_only: PostOnly,
+    },
+    CancelOrder {
+        order_id: OrderId,
+    },
+    QueryBook {
+        ticker: String,
+        #[serde(default = "default_levels")]
+        levels: usize,
+    },
+    QueryClient {
+        name: String,
+    },
+    QueryStatus {
+        ticker: String,
+    },
+    /// Indicative price, volume and imbalance of an auction call.
+    QueryAuction {
+        ticker: String,
+    },
+}
+
+fn default_levels() -> usize {
+    5
+}
+
+fn default_tick_size() -> Price {
+    Price::from_minor(1)
+}
+
+fn default_lot_size() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResponseLine {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Execution(ExecutionReport),
+    /// A resting order traded, sent to the session that placed it.
+    Fill(Fill),
+    Cancelled {
+        order: Order,
+    },
+    Book {
+        ticker: String,
+        #[serde(flatten)]
+        depth: Depth,
+    },
+    Client(ClientView),
+    Status {
+        ticker: String,
+        status: TradingStatus,
+    },
+    /// The auction ended, every fill is sent to both of its sessions.
+    Auction(AuctionReport),
+    /// No price fields when nothing would trade.
+    Indicative {
+        ticker: String,
+        #[serde(flatten)]
+        auction: Option<AuctionPrice>,
+    },
+    Error {
+        error: ExchangeError,
+        message: String,
+    },
+}
+
+impl ResponseLine {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl From<ExchangeError> for Response {
+    fn from(error: ExchangeError) -> Self {
+        Response::Error {
+            message: error.to_string(),
+            error,
+        }
+    }
+}
+
+/// Balances of one client as answered to `query_client`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientView {
+    pub name: String,
+    pub balance: Money,
+    pub reserved_balance: Money,
+    pub stocks: BTreeMap<String, u32>,
+    pub reserved_stocks: BTreeMap<String, u32>,
+}
+
+impl From<&Client> for ClientView {
+    fn from(client: &Client) -> Self {
+        let collect = |stocks: Vec<(&String, &u32)>| {
+            stocks
+                .into_iter()
+                .map(|(ticker, amount)| (ticker.clone(), *amount))
+                .collect()
+        };
+        ClientView {
+            name: client.get_name().to_string(),
+            balance: client.get_balance(),
+            reserved_balance: client.get_reserved_balance(),
+            stocks: collect(client.get_stocks()),
+            reserved_stocks: collect(client.get_reserved_stocks()),
+        }
+    }
+}
+
+impl Request {
+    /// Queries leave the exchange as it is and are not journaled.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            Request::QueryBook { .. }
+                | Request::QueryClient { .. }
+                | Request::QueryStatus { .. }
+                | Request::QueryAuction { .. }
+        )
+    }
+
+    /// The ticker whose book the request changes, if any. An order is
+    /// looked up by its id.
+    pub fn ticker(&self, orders_db: &OrdersDb) -> Option<String> {
+        match self {
+            Request::SetTickerRules { ticker, .. }
+            | Request::SetTradingStatus { ticker, .. }
+            | Request::EndAuction { ticker, .. }
+            | Request::SetPriceBand { ticker, .. }
+            | Request::SetCircuitBreaker { ticker, .. }
+            | Request::PlaceOrder { ticker, .. }
+            | Request::QueryBook { ticker, .. }
+            | Request::QueryStatus { ticker }
+            | Request::QueryAuction { ticker } => Some(ticker.clone()),
+            Request::CancelOrder { order_id } => orders_db.get_ticker_of(*order_id),
+            Request::AddTicker { .. }
+            | Request::AddClient { .. }
+            | Request::Deposit { .. }
+            | Request::Withdraw { .. }
+            | Request::QueryClient { .. } => None,
+        }
+    }
+
+    /// Runs the request against the exchange. Failures come back as
+    /// `Response::Error`.
+    pub async fn execute(self, orders_db: OrdersDb, clients_db: ClientsDb) -> Response {
+        self.try_execute(orders_db, clients_db)
+            .await
+            .unwrap_or_else(Response::from)
+    }
+
+    async fn try_execute(self, orders_db: OrdersDb, clients_db: ClientsDb) -> Result<Response> {
+        match self {
+            Request::AddTicker { ticker } => {
+                ExchangeOperation::add_ticker(orders_db, &ticker).await?;
+                Ok(Response::Ok)
+            }
+            Request::AddClient {
+                name,
+                balance,
+                stocks,
+            } => {
+                let mut client = Client::new(&name, balance);
+                for (ticker, amount) in &stocks {
+                    if orders_db.get_book(ticker).is_none() {
+                        return Err(ExchangeError::UnknownTicker);
+                    }
+                    client.deposit_stock(ticker, *amount)?;
+                }
+                ExchangeOperation::add_client(clients_db, client).await?;
+                Ok(Response::Ok)
+            }
+            Request::SetTickerRules {
+                ticker,
+                tick_size,
+                lot_size,
+            } => {
+                ExchangeOperation::set_ticker_rules(orders_db, &ticker, tick_size, lot_size)
+                    .await?;
+                Ok(Response::Ok)
+            }
+            Request::SetTradingStatus { ticker, status } => {
+                ExchangeOperation::set_trading_status(orders_db, &ticker, status).await?;
+                Ok(Response::Ok)
+            }
+            Request::EndAuction { ticker, status } => {
+                let report =
+                    ExchangeOperation::end_auction(orders_db, clients_db, &ticker, status).await?;
+                Ok(Response::Auction(report))
+            }
+            Request::SetPriceBand { ticker, price_band } => {
+                ExchangeOperation::set_price_band(orders_db, &ticker, price_band).await?;
+                Ok(Response::Ok)
+            }
+            Request::SetCircuitBreaker {
+                ticker,
+                circuit_breaker,
+            } => {
+                ExchangeOperation::set_circuit_breaker(orders_db, &ticker, circuit_breaker).await?;
+                Ok(Response::Ok)
+            }
+            Request::Deposit {
+                client,
+                ticker,
+                amount,
+            } => {
+                ExchangeOperation::deposit(
+                    orders_db,
+                    clients_db,
+                    &client,
+                    ticker.as_deref(),
+                    amount,
+                )
+                .await?;
+                Ok(Response::Ok)
+            }
+            Request::Withdraw {
+                client,
+                ticker,
+                amount,
+            } => {
+                ExchangeOperation::withdraw(
+                    orders_db,
+                    clients_db,
+                    &client,
+                    ticker.as_deref(),
+                    amount,
+                )
+                .await?;
+                Ok(Response::Ok)
+            }
+            Request::PlaceOrder {
+                client,
+                side,
+                ticker,
+                price,
+                amount,
+                order_type,
+                stop_price,
+                display_amount,
+                time_in_force,
+                self_trade_prevention,
+                post_only,
+            } => {
+                let mut order = match order_type {
+                    OrderType::Limit => Order::new(&client, side, &ticker, price, amount),
+                    OrderType::Market => Order::new_market(&client, side, &ticker, amount),
+                    OrderType::Stop => Order::new_stop(&client, side, &ticker, stop_price, amount),
+                    OrderType::StopLimit => {
+                        Order::new_stop_limit(&client, side, &ticker, stop_price, price, amount)
+                    }
+                };
+                order.set_time_in_force(time_in_force);
+                order.set_self_trade_prevention(self_trade_prevention);
+                order.set_display_amount(display_amount);
+                order.set_post_only(post_only);
+                let report = ExchangeOperation::operate(orders_db, clients_db, order).await?;
+                Ok(Response::Execution(report))
+            }
+            Request::CancelOrder { order_id } => {
+                let order = ExchangeOperation::cancel(orders_db, clients_db, order_id).await?;
+                Ok(Response::Cancelled { order })
+            }
+            Request::QueryBook { ticker, levels } => {
+                let depth = ExchangeOperation::depth(orders_db, &ticker, levels).await?;
+                Ok(Response::Book { ticker, depth })
+            }
+            Request::QueryClient { name } => {
+                let client = ExchangeOperation::get_client(clients_db, &name).await?;
+                Ok(Response::Client(ClientView::from(&client)))
+            }
+            Request::QueryStatus { ticker } => {
+                let status = ExchangeOperation::trading_status(orders_db, &ticker).await?;
+                Ok(Response::Status { ticker, status })
+            }
+            Request::QueryAuction { ticker } => {
+                let auction = ExchangeOperation::indicative_auction(orders_db, &ticker).await?;
+                Ok(Response::Indicative { ticker, auction })
+            }
+        }
+    }
+}
+
+/// Parses one JSON line and runs it. Blank lines get no response, lines
+/// that do not parse get an `InvalidRequest` error. With a journal every
+/// request that is not a query is appended to it before it runs, see
+/// `JournalBase::execute`.
+pub async fn respond(
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    journal: Option<&JournalDb>,
+    line: &str,
+) -> Option<ResponseLine> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    let response = match serde_json::from_str::<RequestLine>(line) {
+        Ok(RequestLine { id, request }) => {
+            let response = match journal {
+                Some(journal) if !request.is_query() => {
+                    journal.execute(request, orders_db, clients_db).await
+                }
+                _ => request.execute(orders_db, clients_db).await,
+            };
+            ResponseLine { id, response }
+        }
+        Err(er) => ResponseLine {
+            id: None,
+            response: ExchangeError::InvalidRequest(er.to_string()).into(),
+        },
+    };
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    async fn send(orders_db: &OrdersDb, clients_db: &ClientsDb, request: Value) -> Value {
+        let line = respond(
+            orders_db.clone(),
+            clients_db.clone(),
+            None,
+            &request.to_string(),
+        )
+        .await
+        .unwrap();
+        serde_json::from_str(&line.to_json()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_client", "name": "C1", "balance": 100, "stocks": {"A": 10}}),
+        )
+        .await;
+        assert_eq!(response["error"], "UnknownTicker");
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_ticker", "ticker": "A"}),
+        )
+        .await;
+        assert_eq!(response, json!({"type": "ok"}));
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"id": 1, "type": "add_client", "name": "C1", "balance": 100, "stocks": {"A": 10}}),
+        )
+        .await;
+        assert_eq!(response, json!({"id": 1, "type": "ok"}));
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_client", "name": "C2", "balance": 100}),
+        )
+        .await;
+        assert_eq!(response, json!({"type": "ok"}));
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "place_order", "client": "C1", "side": "sell", "ticker": "A", "price": 5, "amount": 4}),
+        )
+        .await;
+        assert_eq!(response["type"], "execution");
+        assert_eq!(response["order_id"], 1);
+        assert_eq!(response["status"], "rested");
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "place_order", "client": "C2", "side": "buy", "ticker": "A", "amount": 1, "order_type": "market"}),
+        )
+        .await;
+        assert_eq!(response["status"], "filled");
+        assert_eq!(response["fills"][0]["maker"], "C1");
+        assert_eq!(response["fills"][0]["price"], "5");
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "query_book", "ticker": "A", "levels": 1}),
+        )
+        .await;
+        assert_eq!(
+            response,
+            json!({"type": "book", "ticker": "A", "bids": [], "asks": [{"price": "5", "amount": 3, "orders": 1}]})
+        );
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "query_client", "name": "C1"}),
+        )
+        .await;
+        assert_eq!(
+            response,
+            json!({"type": "client", "name": "C1", "balance": "105", "reserved_balance": "0",
+                   "stocks": {"A": 9}, "reserved_stocks": {"A": 3}})
+        );
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "cancel_order", "order_id": 1}),
+        )
+        .await;
+        assert_eq!(response["type"], "cancelled");
+        assert_eq!(response["order"]["amount"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_withdraw_and_errors() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_ticker", "ticker": "A"}),
+        )
+        .await;
+        send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_client", "name": "C1", "stocks": {"A": 1}}),
+        )
+        .await;
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "deposit", "client": "C1", "amount": 50}),
+        )
+        .await;
+        assert_eq!(response, json!({"type": "ok"}));
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "withdraw", "client": "C1", "ticker": "A", "amount": 2}),
+        )
+        .await;
+        assert_eq!(response["error"], "NotEnoughStocks");
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "deposit", "client": "C1", "ticker": "Z", "amount": 2}),
+        )
+        .await;
+        assert_eq!(response["error"], "UnknownTicker");
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"id": 7, "type": "withdraw", "client": "C9", "amount": 2}),
+        )
+        .await;
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"], "UnknownUser");
+        assert_eq!(response["message"], "Unknown user in operation");
+
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "place_order", "client": "C1", "side": "buy", "ticker": "A", "price": 60, "amount": 1}),
+        )
+        .await;
+        assert_eq!(
+            response["status"],
+            json!({"rejected": "BuyerDoesntHaveEnoughMoney"})
+        );
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "set_ticker_rules", "ticker": "A", "tick_size": "0.25"}),
+        )
+        .await;
+        assert_eq!(response, json!({"type": "ok"}));
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "set_ticker_rules", "ticker": "AA", "tick_size": "0.25"}),
+        )
+        .await;
+        assert_eq!(response["error"], "UnknownTicker");
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "place_order", "client": "C1", "side": "buy", "ticker": "A", "price": "10.1", "amount": 1}),
+        )
+        .await;
+        assert_eq!(response["status"], json!({"rejected": {"OffTick": "0.25"}}));
+
+        let line = respond(
+            orders_db.clone(),
+            clients_db.clone(),
+            None,
+            "{\"type\":\"nope\"}",
+        )
+        .await
+        .unwrap();
+        assert!(line.to_json().contains("InvalidRequest"));
+        assert_eq!(respond(orders_db, clients_db, None, "  ").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_trading_status_requests() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_ticker", "ticker": "A"}),
+        )
+        .await;
+        send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "add_client", "name": "C1", "balance": 100, "stocks": {"A": 10}}),
+        )
+        .await;
+
+        for request in [
+            json!({"type": "set_trading_status", "ticker": "A", "status": "halted"}),
+            json!({"type": "set_price_band", "ticker": "A", "reference_price": 10, "width_bps": 500}),
+            json!({"type": "set_price_band", "ticker": "A"}),
+            json!({"type": "set_circuit_breaker", "ticker": "A", "move_bps": 1000, "window": 10}),
+        ] {
+            assert_eq!(
+                send(&orders_db, &clients_db, request).await,
+                json!({"type": "ok"})
+            );
+        }
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "query_status", "ticker": "A"}),
+        )
+        .await;
+        assert_eq!(
+            response,
+            json!({"type": "status", "ticker": "A", "status": "halted"})
+        );
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "place_order", "client": "C1", "side": "sell", "ticker": "A", "price": 5, "amount": 1}),
+        )
+        .await;
+        assert_eq!(response["status"]["rejected"], "TickerHalted");
+        let response = send(
+            &orders_db,
+            &clients_db,
+            json!({"type": "set_trading_status", "ticker": "B", "status": "open"}),
+        )
+        .await;
+        assert_eq!(response["error"], "UnknownTicker");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{AuctionPrice, ExchangeError, OrderId, Price};
+use serde::Serialize;
+
+/// One trade between a resting (maker) and an incoming (taker) order.
+/// In an auction both orders rested, the older one is the maker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fill {
+    pub maker: String,
+    pub taker: String,
+    pub ticker: String,
+    pub price: Price,
+    pub amount: u32,
+    pub maker_order_id: OrderId,
+    pub taker_order_id: OrderId,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// Accepted, not matched yet, e.g. a stop order waiting for its stop
+    /// price.
+    New,
+    /// Traded in part, the rest is on the book.
+    PartiallyFilled,
+    Filled,
+    /// Nothing traded, the whole order is on the book.
+    Rested,
+    /// Taken off without resting, e.g. by self-trade prevention.
+    Cancelled,
+    Rejected(ExchangeError),
+}
+
+/// What happened to an order passed to `ExchangeOperation::operate`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExecutionReport {
+    pub order_id: OrderId,
+    pub status: OrderStatus,
+    pub fills: Vec<Fill>,
+    /// Stop orders this order's trades activated, in the order they were
+    /// placed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub triggered: Vec<ExecutionReport>,
+}
+
+/// What ending a call auction traded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuctionReport {
+    pub ticker: String,
+    /// Where the book uncrossed, none when nothing crossed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction: Option<AuctionPrice>,
+    pub fills: Vec<Fill>,
+    /// Stop orders the auction price activated, placed once trading opened.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub triggered: Vec<ExecutionReport>,
+}
+
+impl ExecutionReport {
+    pub fn new(order_id: OrderId) -> Self {
+        ExecutionReport {
+            order_id,
+            status: OrderStatus::New,
+            fills: Vec::new(),
+            triggered: Vec::new(),
+        }
+    }
+
+    pub fn rejected(order_id: OrderId, reason: ExchangeError) -> Self {
+        ExecutionReport {
+            order_id,
+            status: OrderStatus::Rejected(reason),
+            fills: Vec::new(),
+            triggered: Vec::new(),
+        }
+    }
+
+    pub fn filled_amount(&self) -> u32 {
+        self.fills.iter().map(|fill| fill.amount).sum()
+    }
+}
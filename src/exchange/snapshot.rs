@@ -0,0 +1,292 @@
+use crate::{
+    CircuitBreaker, Client, ClientsDb, ExchangeError, Operation, Order, OrderBook, OrderId,
+    OrdersDb, Price, PriceBand, Result, TradingStatus,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever the layout of `Snapshot` changes.
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// Whole state of the exchange after journal entry `journal_sequence`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub journal_sequence: u64,
+    pub last_order_id: OrderId,
+    pub market_sequence: u64,
+    /// Sorted by name.
+    pub clients: Vec<Client>,
+    pub books: BTreeMap<String, BookSnapshot>,
+}
+
+/// Rules and resting orders of one listed ticker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookSnapshot {
+    pub tick_size: Price,
+    pub lot_size: u32,
+    /// Bids then asks, best price first and oldest first within a price
+    /// level.
+    pub orders: Vec<Order>,
+    /// Stop orders waiting for their stop price.
+    pub stops: Vec<Order>,
+    pub last_price: Option<Price>,
+    pub status: TradingStatus,
+    pub price_band: Option<PriceBand>,
+    /// With the recent trades it has seen.
+    pub circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl Snapshot {
+    pub fn take(orders_db: &OrdersDb, clients_db: &ClientsDb, journal_sequence: u64) -> Self {
+        // every book stays locked until the clients are read, so the
+        // snapshot sees no order half way through matching
+        let books = orders_db.get_books();
+        let locked: Vec<_> = books
+            .iter()
+            .map(|(ticker, book)| (ticker, book.lock().unwrap()))
+            .collect();
+        let clients = clients_db.get_all();
+        let books = locked
+            .iter()
+            .map(|(ticker, book)| {
+                let orders = book
+                    .orders(Operation::Buy)
+                    .chain(book.orders(Operation::Sell))
+                    .cloned()
+                    .collect();
+                let book = BookSnapshot {
+                    tick_size: book.get_tick_size(),
+                    lot_size: book.get_lot_size(),
+                    orders,
+                    stops: book.get_triggers().orders().cloned().collect(),
+                    last_price: book.get_last_price(),
+                    status: book.get_status(),
+                    price_band: book.get_price_band(),
+                    circuit_breaker: book.get_circuit_breaker().cloned(),
+                };
+                (ticker.to_string(), book)
+            })
+            .collect();
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            journal_sequence,
+            last_order_id: orders_db.get_last_order_id(),
+            market_sequence: orders_db.get_publisher().get_last_sequence(),
+            clients,
+            books,
+        }
+    }
+
+    /// Replaces the contents of both databases with the snapshot.
+    pub fn restore(self, orders_db: &OrdersDb, clients_db: &ClientsDb) -> Result<()> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(ExchangeError::UnsupportedSnapshot(self.version));
+        }
+        let mut books = HashMap::with_capacity(self.books.len());
+        for (ticker, snapshot) in self.books {
+            let mut book = OrderBook::new();
+            book.set_tick_size(snapshot.tick_size);
+            book.set_lot_size(snapshot.lot_size);
+            book.set_last_price(snapshot.last_price);
+            // the status first, opening resets the breaker
+            book.set_status(snapshot.status);
+            book.set_price_band(snapshot.price_band);
+            book.set_circuit_breaker(snapshot.circuit_breaker);
+            for order in snapshot.orders {
+                book.insert(order);
+            }
+            for order in snapshot.stops {
+                book.get_triggers_mut().insert(order);
+            }
+            books.insert(ticker, book);
+        }
+        orders_db.restore(books, self.last_order_id, self.market_sequence);
+        clients_db.replace(self.clients);
+        Ok(())
+    }
+
+    /// Writes `snapshot-<journal sequence>.json` into `dir`. The file is
+    /// synced before it is renamed into place and the directory after, so
+    /// a crash never leaves half a snapshot under that name.
+    pub fn save(&self, dir: &Path) -> Result<PathBuf> {
+        let path = dir.join(format!("snapshot-{:020}.json", self.journal_sequence));
+        let temp = path.with_extension("tmp");
+        let file_error = |_| ExchangeError::ProblemWithFile(path.display().to_string());
+        fs::create_dir_all(dir).map_err(file_error)?;
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        let mut file = File::create(&temp).map_err(file_error)?;
+        file.write_all(&json).map_err(file_error)?;
+        file.sync_all().map_err(file_error)?;
+        fs::rename(&temp, &path).map_err(file_error)?;
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(file_error)?;
+        Ok(path)
+    }
+
+    /// The newest snapshot in `dir` that can be read, if any.
+    pub fn load_latest(dir: &Path) -> Result<Option<Snapshot>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(None),
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("snapshot-") && name.ends_with(".json"))
+            })
+            .collect();
+        paths.sort();
+        for path in paths.iter().rev() {
+            let snapshot = fs::read(path)
+                .ok()
+                .and_then(|json| serde_json::from_slice::<Snapshot>(&json).ok());
+            if let Some(snapshot) = snapshot {
+                return Ok(Some(snapshot));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExchangeOperation, SelfTradePrevention};
+
+    async fn setup() -> (OrdersDb, ClientsDb) {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for ticker in ["A", "B"] {
+            ExchangeOperation::add_ticker(orders_db.clone(), ticker)
+                .await
+                .unwrap();
+        }
+        ExchangeOperation::set_ticker_rules(orders_db.clone(), "B", "0.05".parse().unwrap(), 5)
+            .await
+            .unwrap();
+        ExchangeOperation::set_trading_status(orders_db.clone(), "B", TradingStatus::Halted)
+            .await
+            .unwrap();
+        let band = PriceBand {
+            reference_price: 10.into(),
+            width_bps: 5000,
+        };
+        ExchangeOperation::set_price_band(orders_db.clone(), "A", Some(band))
+            .await
+            .unwrap();
+        for name in ["C1", "C2"] {
+            let mut client = Client::new(name, 1000.into());
+            client.deposit_stock("A", 100).unwrap();
+            ExchangeOperation::add_client(clients_db.clone(), client)
+                .await
+                .unwrap();
+        }
+        for order in [
+            Order::new("C1", Operation::Sell, "A", 10.into(), 5),
+            Order::new("C2", Operation::Sell, "A", 10.into(), 6),
+            Order::new("C1", Operation::Sell, "A", 11.into(), 7),
+            Order::new("C2", Operation::Buy, "A", 8.into(), 2),
+            Order::new("C1", Operation::Buy, "A", 9.into(), 3),
+            Order::new_stop_limit("C2", Operation::Sell, "A", 8.into(), 7.into(), 4),
+        ] {
+            ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order)
+                .await
+                .unwrap();
+        }
+        (orders_db, clients_db)
+    }
+
+    #[tokio::test]
+    async fn test_take_and_restore() {
+        let (orders_db, clients_db) = setup().await;
+        let snapshot = Snapshot::take(&orders_db, &clients_db, 42);
+        assert_eq!(snapshot.journal_sequence, 42);
+        assert_eq!(snapshot.last_order_id, 6);
+        assert_eq!(snapshot.books["B"].orders, vec![]);
+        assert_eq!(snapshot.books["B"].tick_size, "0.05".parse().unwrap());
+        assert_eq!(snapshot.books["B"].lot_size, 5);
+        let ids: Vec<OrderId> = snapshot.books["A"]
+            .orders
+            .iter()
+            .map(Order::get_id)
+            .collect();
+        assert_eq!(ids, vec![5, 4, 1, 2, 3]);
+        assert_eq!(snapshot.books["A"].stops[0].get_id(), 6);
+        assert_eq!(snapshot.books["A"].last_price, None);
+        assert_eq!(snapshot.books["B"].status, TradingStatus::Halted);
+        assert_eq!(snapshot.books["A"].price_band.unwrap().width_bps, 5000);
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored_orders = OrdersDb::default();
+        let restored_clients = ClientsDb::default();
+        serde_json::from_str::<Snapshot>(&json)
+            .unwrap()
+            .restore(&restored_orders, &restored_clients)
+            .unwrap();
+        assert_eq!(
+            Snapshot::take(&restored_orders, &restored_clients, 42),
+            snapshot
+        );
+        let client = ExchangeOperation::get_client(restored_clients.clone(), "C1")
+            .await
+            .unwrap();
+        assert_eq!(client.get_reserved_stock("A"), 12);
+        assert_eq!(client.get_reserved_balance(), 27.into());
+
+        // FIFO at 10 survived: C1's order trades before C2's
+        let mut order = Order::new("C2", Operation::Buy, "A", 10.into(), 5);
+        order.set_self_trade_prevention(SelfTradePrevention::CancelNewest);
+        let report =
+            ExchangeOperation::operate(restored_orders.clone(), restored_clients.clone(), order)
+                .await
+                .unwrap();
+        assert_eq!(report.order_id, 7);
+        assert_eq!(report.fills[0].maker_order_id, 1);
+        assert!(report.triggered.is_empty());
+        assert_eq!(
+            ExchangeOperation::get_client(restored_clients.clone(), "C2")
+                .await
+                .unwrap()
+                .get_reserved_stock("A"),
+            10
+        );
+        assert_eq!(
+            restored_orders.get_publisher().get_last_sequence(),
+            snapshot.market_sequence + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_latest() {
+        let dir = std::env::temp_dir().join("victorparamonov_snapshots");
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(Snapshot::load_latest(&dir), Ok(None));
+
+        let (orders_db, clients_db) = setup().await;
+        Snapshot::take(&orders_db, &clients_db, 9)
+            .save(&dir)
+            .unwrap();
+        let newest = Snapshot::take(&orders_db, &clients_db, 10);
+        newest.save(&dir).unwrap();
+        assert_eq!(Snapshot::load_latest(&dir), Ok(Some(newest)));
+
+        fs::write(dir.join("snapshot-00000000000000000011.json"), "{").unwrap();
+        let loaded = Snapshot::load_latest(&dir).unwrap().unwrap();
+        assert_eq!(loaded.journal_sequence, 10);
+
+        let mut old = loaded;
+        old.version = 0;
+        assert_eq!(
+            old.restore(&orders_db, &clients_db),
+            Err(ExchangeError::UnsupportedSnapshot(0))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
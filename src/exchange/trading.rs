@@ -0,0 +1,262 @@
+use crate::{Operation, Price};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+/// Basis points in one whole, 100 bps are 1%.
+const BPS: u128 = 10_000;
+
+/// Whether a ticker takes new orders. Cancels work in every status.
+#[derive(PartialEq, Clone, Copy, Debug, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingStatus {
+    #[default]
+    Open,
+    /// Stopped by an admin or a circuit breaker until resumed.
+    Halted,
+    /// Not trading, e.g. outside the trading day.
+    Closed,
+    /// Call period of an auction: orders rest without matching until the
+    /// auction is uncrossed.
+    Auction,
+}
+
+/// Static band of limit prices allowed around a reference price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceBand {
+    pub reference_price: Price,
+    /// Half width of the band in basis points of the reference price.
+    pub width_bps: u32,
+}
+
+impl PriceBand {
+    pub fn contains(&self, price: Price) -> bool {
+        let reference = self.reference_price.minor() as u128;
+        let offset = reference * self.width_bps as u128 / BPS;
+        let price = price.minor() as u128;
+        reference.saturating_sub(offset) <= price && price <= reference + offset
+    }
+}
+
+/// Halts a ticker when trade prices move more than `move_bps` within the
+/// last `window` trades. The window counts trades rather than time, so
+/// replaying the journal trips it at the same trades.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    pub move_bps: u32,
+    pub window: usize,
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    recent: VecDeque<Price>,
+}
+
+impl CircuitBreaker {
+    pub fn new(move_bps: u32, window: usize) -> Self {
+        CircuitBreaker {
+            move_bps,
+            window: window.max(1),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records a trade price and tells whether the breaker trips.
+    pub fn record(&mut self, price: Price) -> bool {
+        self.recent.push_back(price);
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+        let (low, high) = match (self.recent.iter().min(), self.recent.iter().max()) {
+            (Some(low), Some(high)) => (low.minor() as u128, high.minor() as u128),
+            _ => return false,
+        };
+        (high - low) * BPS > self.move_bps as u128 * low
+    }
+
+    /// Forgets past trades, e.g. when trading resumes.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+}
+
+/// What a call auction would trade at one price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AuctionPrice {
+    pub price: Price,
+    /// Units that trade at `price`.
+    pub volume: u64,
+    /// Units left over on `imbalance_side`.
+    pub imbalance: u64,
+    /// The side with more interest at `price`, none when both match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imbalance_side: Option<Operation>,
+}
+
+/// The price a call auction uncrosses at, from the `(limit price, amount)`
+/// levels of both sides. It trades the most; ties go to the smallest
+/// imbalance, then to the surplus side (the highest price when buyers are
+/// left over at every tied price, the lowest when sellers are), then to
+/// the price closest to `reference_price` and finally to the lowest.
+/// Prices outside `price_band` are left out. `None` when the sides do not
+/// cross inside it.
+pub fn auction_price(
+    bids: &[(Price, u64)],
+    asks: &[(Price, u64)],
+    price_band: Option<PriceBand>,
+    reference_price: Option<Price>,
+) -> Option<AuctionPrice> {
+    let mut prices: Vec<Price> = bids.iter().chain(asks).map(|(price, _)| *price).collect();
+    prices.retain(|price| price_band.is_none_or(|band| band.contains(*price)));
+    prices.sort();
+    prices.dedup();
+    let mut candidates: Vec<AuctionPrice> = prices
+        .into_iter()
+        .map(|price| {
+            let demand: u64 = bids
+                .iter()
+                .filter(|(limit, _)| *limit >= price)
+                .map(|(_, amount)| amount)
+                .sum();
+            let supply: u64 = asks
+                .iter()
+                .filter(|(limit, _)| *limit <= price)
+                .map(|(_, amount)| amount)
+                .sum();
+            AuctionPrice {
+                price,
+                volume: demand.min(supply),
+                imbalance: demand.abs_diff(supply),
+                imbalance_side: match demand.cmp(&supply) {
+                    Ordering::Greater => Some(Operation::Buy),
+                    Ordering::Less => Some(Operation::Sell),
+                    Ordering::Equal => None,
+                },
+            }
+        })
+        .filter(|candidate| candidate.volume > 0)
+        .collect();
+
+    let volume = candidates.iter().map(|candidate| candidate.volume).max()?;
+    candidates.retain(|candidate| candidate.volume == volume);
+    let imbalance = candidates
+        .iter()
+        .map(|candidate| candidate.imbalance)
+        .min()?;
+    candidates.retain(|candidate| candidate.imbalance == imbalance);
+    let surplus = |side| {
+        candidates
+            .iter()
+            .all(|candidate| candidate.imbalance_side == Some(side))
+    };
+    if surplus(Operation::Buy) {
+        return candidates.last().copied();
+    }
+    if surplus(Operation::Sell) {
+        return candidates.first().copied();
+    }
+    match reference_price {
+        Some(reference) => candidates
+            .into_iter()
+            .min_by_key(|candidate| candidate.price.minor().abs_diff(reference.minor())),
+        None => candidates.first().copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_band() {
+        let band = PriceBand {
+            reference_price: 100.into(),
+            width_bps: 500,
+        };
+        assert!(band.contains(95.into()));
+        assert!(band.contains(105.into()));
+        assert!(!band.contains("94.99".parse().unwrap()));
+        assert!(!band.contains("105.01".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::new(1000, 3);
+        assert!(!breaker.record(100.into()));
+        assert!(!breaker.record(105.into()));
+        assert!(!breaker.record(110.into()));
+        // 100 left the window, 105 to 116 is more than 10%
+        assert!(breaker.record(116.into()));
+        breaker.reset();
+        assert!(!breaker.record(90.into()));
+    }
+
+    #[test]
+    fn test_auction_price() {
+        let levels = |levels: &[(u32, u64)]| -> Vec<(Price, u64)> {
+            levels
+                .iter()
+                .map(|(price, amount)| ((*price).into(), *amount))
+                .collect()
+        };
+        let price = |bids: &[(u32, u64)], asks: &[(u32, u64)], reference: Option<u32>| {
+            auction_price(
+                &levels(bids),
+                &levels(asks),
+                None,
+                reference.map(Price::from),
+            )
+        };
+
+        // 11 trades 10, more than 4 at 10 or 5 at 12
+        let auction = price(
+            &[(12, 5), (11, 5), (10, 5)],
+            &[(10, 4), (11, 6), (12, 5)],
+            None,
+        );
+        assert_eq!(
+            auction,
+            Some(AuctionPrice {
+                price: 11.into(),
+                volume: 10,
+                imbalance: 0,
+                imbalance_side: None,
+            })
+        );
+        // buyers are left over at 10 and 11, so the higher price
+        let auction = price(&[(11, 10)], &[(10, 6)], None).unwrap();
+        assert_eq!(auction.price, 11.into());
+        assert_eq!(auction.imbalance, 4);
+        assert_eq!(auction.imbalance_side, Some(Operation::Buy));
+        // sellers are left over, so the lower price
+        let auction = price(&[(11, 6)], &[(10, 10)], Some(11)).unwrap();
+        assert_eq!(auction.price, 10.into());
+        // no surplus, the reference price decides
+        assert_eq!(
+            price(&[(11, 5)], &[(10, 5)], Some(12)).unwrap().price,
+            11.into()
+        );
+        assert_eq!(
+            price(&[(11, 5)], &[(10, 5)], Some(9)).unwrap().price,
+            10.into()
+        );
+        assert_eq!(
+            price(&[(11, 5)], &[(10, 5)], None).unwrap().price,
+            10.into()
+        );
+        // the smaller imbalance wins over the reference price
+        let auction = price(&[(12, 5), (10, 2)], &[(10, 5)], Some(9)).unwrap();
+        assert_eq!(auction.price, 12.into());
+        assert_eq!(auction.imbalance, 0);
+
+        // buyers are left over at 10 and 11, but 11 is outside the band
+        let band = PriceBand {
+            reference_price: 10.into(),
+            width_bps: 500,
+        };
+        let auction = auction_price(&levels(&[(11, 10)]), &levels(&[(10, 6)]), Some(band), None);
+        assert_eq!(auction.unwrap().price, 10.into());
+        let auction = auction_price(&levels(&[(12, 5)]), &levels(&[(11, 5)]), Some(band), None);
+        assert_eq!(auction, None);
+
+        assert_eq!(price(&[(9, 5)], &[(10, 5)], None), None);
+        assert_eq!(price(&[], &[(10, 5)], None), None);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{Operation, Order, OrderId, Price};
+use std::collections::{BTreeMap, HashMap};
+
+/// Stop orders of one ticker waiting for a trade to reach their stop
+/// price. They are not on the order book and not in market data. Both
+/// sides are sorted by stop price, so the orders a trade activates are
+/// always one end of a side.
+#[derive(Debug, Clone, Default)]
+pub struct TriggerBook {
+    buys: BTreeMap<(Price, OrderId), Order>,
+    sells: BTreeMap<(Price, OrderId), Order>,
+    index: HashMap<OrderId, (Operation, Price)>,
+    /// Orders taken off since `take_removed`.
+    removed: Vec<OrderId>,
+}
+
+impl TriggerBook {
+    pub fn new() -> Self {
+        TriggerBook::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, order_id: OrderId) -> bool {
+        self.index.contains_key(&order_id)
+    }
+
+    pub fn get(&self, order_id: OrderId) -> Option<&Order> {
+        let (operation, stop_price) = self.index.get(&order_id)?;
+        self.side(*operation).get(&(*stop_price, order_id))
+    }
+
+    /// Adds a stop order. Orders without a stop price are ignored.
+    pub fn insert(&mut self, order: Order) {
+        let stop_price = match order.get_stop_price() {
+            Some(stop_price) => stop_price,
+            None => return,
+        };
+        let operation = order.get_operation();
+        self.index.insert(order.get_id(), (operation, stop_price));
+        self.side_mut(operation)
+            .insert((stop_price, order.get_id()), order);
+    }
+
+    pub fn remove(&mut self, order_id: OrderId) -> Option<Order> {
+        let (operation, stop_price) = self.index.remove(&order_id)?;
+        self.removed.push(order_id);
+        self.side_mut(operation).remove(&(stop_price, order_id))
+    }
+
+    /// Takes off every order a trade at `last_price` activates, oldest
+    /// first.
+    pub fn take_triggered(&mut self, last_price: Price) -> Vec<Order> {
+        let mut triggered: Vec<Order> = Vec::new();
+        // everything at or below the last price is a bought-through stop
+        let above = self.buys.split_off(&(last_price, OrderId::MAX));
+        triggered.extend(std::mem::replace(&mut self.buys, above).into_values());
+        let above = self.sells.split_off(&(last_price, OrderId::MIN));
+        triggered.extend(above.into_values());
+        for order in &triggered {
+            self.index.remove(&order.get_id());
+            self.removed.push(order.get_id());
+        }
+        triggered.sort_by_key(Order::get_id);
+        triggered
+    }
+
+    /// Ids of the orders taken off since the last call.
+    pub fn take_removed(&mut self) -> Vec<OrderId> {
+        std::mem::take(&mut self.removed)
+    }
+
+    /// Buy stops then sell stops, each in the order a moving price would
+    /// reach them.
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.buys.values().chain(self.sells.values().rev())
+    }
+
+    fn side(&self, operation: Operation) -> &BTreeMap<(Price, OrderId), Order> {
+        match operation {
+            Operation::Buy => &self.buys,
+            Operation::Sell => &self.sells,
+        }
+    }
+
+    fn side_mut(&mut self, operation: Operation) -> &mut BTreeMap<(Price, OrderId), Order> {
+        match operation {
+            Operation::Buy => &mut self.buys,
+            Operation::Sell => &mut self.sells,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(id: OrderId, operation: Operation, stop_price: u32) -> Order {
+        let mut order = Order::new_stop("C", operation, "A", stop_price.into(), 1);
+        order.set_id(id);
+        order
+    }
+
+    #[test]
+    fn test_take_triggered() {
+        let mut book = TriggerBook::new();
+        book.insert(stop(1, Operation::Buy, 12));
+        book.insert(stop(2, Operation::Buy, 11));
+        book.insert(stop(3, Operation::Buy, 13));
+        book.insert(stop(4, Operation::Sell, 9));
+        book.insert(stop(5, Operation::Sell, 10));
+        book.insert(Order::new("C", Operation::Buy, "A", 1.into(), 1));
+        assert_eq!(book.len(), 5);
+
+        let ids = |orders: Vec<Order>| orders.iter().map(Order::get_id).collect::<Vec<OrderId>>();
+        assert!(book.take_triggered("10.5".parse().unwrap()).is_empty());
+        assert_eq!(ids(book.take_triggered(12.into())), vec![1, 2]);
+        assert_eq!(ids(book.take_triggered(10.into())), vec![5]);
+        assert!(!book.contains(1));
+        assert!(book.contains(3));
+        assert_eq!(
+            book.orders().map(Order::get_id).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(book.remove(4).unwrap().get_id(), 4);
+        assert_eq!(book.remove(4), None);
+        assert_eq!(book.get(3).unwrap().get_stop_price(), Some(13.into()));
+        assert_eq!(book.len(), 1);
+    }
+}
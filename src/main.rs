@@ -1,148 +1,220 @@
 pub mod exchange;
 pub use exchange::*;
-// use std::fs::File;
-// use std::io::{self, BufRead, Write};
-// use std::path::Path;
-use std::collections::HashMap;
-use std::fs::File as std_file;
-use std::io::prelude::*;
-use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+mod bench;
+mod cli;
+mod report;
+mod server;
+mod tape;
+
+use clap::Parser;
+use cli::{BookArgs, Cli, Command, JournalArgs, ReplayArgs, RequestsArgs, ServeArgs, TapeArgs};
+use std::sync::Arc;
 use std::time::Instant;
-use tokio::{
-    fs::File,
-    io::{AsyncBufReadExt, BufReader},
-};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let orders_db: OrdersDb = Arc::new(Mutex::new(HashMap::new()));
-    let clients_db: ClientsDb = Arc::new(Mutex::new(HashMap::new()));
+async fn main() {
+    let cli = Cli::parse();
+    let verbose = cli.verbose;
+    let result = match cli.command {
+        Some(Command::Replay(args)) => replay(args, verbose).await,
+        Some(Command::Validate(args)) => validate(args).await,
+        Some(Command::Book(args)) => book(args, verbose).await,
+        Some(Command::Requests(args)) => requests(args, verbose).await,
+        Some(Command::Serve(args)) => serve(args, verbose).await,
+        Some(Command::Bench(args)) => bench::run(&args).await,
+        None => replay(ReplayArgs::default(), verbose).await,
+    };
+    if let Err(er) = result {
+        eprintln!("error: {}", er);
+        std::process::exit(1);
+    }
+}
+
+/// Loads the clients and replays the orders tape into fresh databases.
+async fn run_tapes(tapes: &TapeArgs, verbose: u8) -> Result<(OrdersDb, ClientsDb)> {
+    let orders_db = OrdersDb::default();
+    let clients_db = ClientsDb::default();
     let start = Instant::now();
-    let file = File::open("./Clients.txt")
-        .await
-        .expect("Failed to open file clients");
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    while let Some(line) = lines.next_line().await.expect("Failed to read file") {
-        let mut iter = line.split_whitespace();
-        let name = match iter.next() {
-            Some(value) => value,
-            None => continue,
-        };
-        let balance = match iter.next() {
-            Some(value) => match value.parse::<u32>() {
-                Ok(res) => res,
-                Err(_err) => {
-                    return Err(ExchangeError::ProblemWithNumber);
-                }
-            },
-            None => continue,
-        };
-        let mut client = Client::new(name, balance);
-        let mut word: u8 = 65;
-        for value in iter {
-            let amount = match value.parse::<u32>() {
-                Ok(res) => res,
-                Err(_err) => {
-                    return Err(ExchangeError::ProblemWithNumber);
-                }
-            };
-            client.update_stock_balance(
-                std::str::from_utf8(&[word]).unwrap(),
-                amount,
-                Operation::Buy,
-            )?; // check later
-            word = match word.checked_add(1) {
-                Some(res) => res,
-                None => {
-                    return Err(ExchangeError::AddOverflow);
-                }
-            };
+    let clients = tape::load_clients(&tapes.clients, orders_db.clone(), clients_db.clone()).await?;
+    if verbose > 0 {
+        eprintln!("{} clients added in {:?}", clients, start.elapsed());
+    }
+    if tapes.opening_auction {
+        for ticker in orders_db.get_tickers() {
+            ExchangeOperation::set_trading_status(
+                orders_db.clone(),
+                &ticker,
+                TradingStatus::Auction,
+            )
+            .await?;
         }
-
-        let clients_db = clients_db.clone();
-        let _ = ExchangeOperation::add_client(clients_db, client).await;
     }
+    let mut stats = tape::replay_orders(
+        &tapes.orders,
+        orders_db.clone(),
+        clients_db.clone(),
+        verbose,
+    )
+    .await?;
+    if tapes.opening_auction {
+        for ticker in orders_db.get_tickers() {
+            let report = ExchangeOperation::end_auction(
+                orders_db.clone(),
+                clients_db.clone(),
+                &ticker,
+                TradingStatus::Open,
+            )
+            .await?;
+            stats.fills += report.fills.len();
+        }
+    }
+    if verbose > 0 {
+        eprintln!(
+            "{} orders, {} rejected, {} fills - working time {:?}",
+            stats.orders,
+            stats.rejected,
+            stats.fills,
+            start.elapsed()
+        );
+    }
+    Ok((orders_db, clients_db))
+}
 
-    let duration = start.elapsed();
-    println!("clients adding amount of time {:?}", duration);
-    let file = File::open("./Orders.txt")
-        .await
-        .expect("Failed to open file Orders");
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    while let Some(line) = lines.next_line().await.expect("Failed to read file") {
-        let mut iter = line.split_whitespace();
-
-        let name = match iter.next() {
-            Some(value) => value,
-            None => continue,
-        };
-
-        let operation = match iter.next() {
-            Some(value) => match Operation::from_str(value) {
-                Ok(value) => value,
-                Err(_err) => {
-                    return Err(ExchangeError::ProblemWithParsingOperation);
-                }
-            },
-            None => continue,
-        };
-
-        let ticker = match iter.next() {
-            Some(value) => value,
-            None => continue,
-        };
-
-        let price = match iter.next() {
-            Some(value) => match value.parse::<u32>() {
-                Ok(res) => res,
-                Err(_err) => {
-                    return Err(ExchangeError::ProblemWithNumber);
-                }
-            },
-            None => continue,
-        };
+async fn replay(args: ReplayArgs, verbose: u8) -> Result<()> {
+    let (orders_db, clients_db) = run_tapes(&args.tapes, verbose).await?;
+    report::write_result(
+        &args.output,
+        orders_db.clone(),
+        clients_db.clone(),
+        args.format,
+    )?;
+    if let Some(expected) = args.compare {
+        let problems = report::compare_result(&expected, orders_db, clients_db)?;
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if !problems.is_empty() {
+            eprintln!("{} differences", problems.len());
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
 
-        let amount = match iter.next() {
-            Some(value) => match value.parse::<u32>() {
-                Ok(res) => res,
-                Err(_err) => {
-                    return Err(ExchangeError::ProblemWithNumber);
-                }
-            },
-            None => continue,
-        };
+async fn validate(args: TapeArgs) -> Result<()> {
+    let problems = tape::validate(&args.clients, &args.orders).await?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        eprintln!("{} bad lines", problems.len());
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-        let order = Order::new(name, operation, ticker, price, amount);
+async fn book(args: BookArgs, verbose: u8) -> Result<()> {
+    let (orders_db, _) = run_tapes(&args.tapes, verbose).await?;
+    let out = report::format_book(orders_db, args.ticker.as_deref(), args.levels, args.format)?;
+    print!("{}", out);
+    Ok(())
+}
 
-        let clients_db = clients_db.clone();
-        let orders_db = orders_db.clone();
-        match ExchangeOperation::operate(orders_db, clients_db, order).await {
-            Ok(_) => (),
-            Err(er) => {
-                println!("error {:?}", er);
-            }
-        };
+/// Opens the journal, if any, and recovers the databases from the latest
+/// snapshot and the journal.
+async fn recover(
+    args: &JournalArgs,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    verbose: u8,
+) -> Result<Option<JournalDb>> {
+    let path = match &args.journal {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let (mut journal, replayed) = Journal::recover(
+        path,
+        args.fsync,
+        args.snapshot_dir.as_deref(),
+        orders_db,
+        clients_db,
+    )
+    .await?;
+    if verbose > 0 {
+        eprintln!("replayed {} journal entries", replayed);
+    }
+    if let Some(dir) = &args.snapshot_dir {
+        journal.set_snapshots(dir.clone(), args.snapshot_every);
     }
-    let duration = start.elapsed();
-    let client_balances = clients_db.lock().unwrap();
-    let mut f = std_file::create("result.txt").expect("Unable to create file");
-    for client in client_balances.values() {
-        let mut stocks = client.get_stocks();
-        stocks.sort_by_key(|k| k.0);
+    Ok(Some(Arc::new(JournalBase::new(journal))))
+}
 
-        let _ = f.write_fmt(format_args!(
-            "{}\t{}\t{}\t{}\t{}\t{}\n",
-            client.get_name(),
-            client.get_balance(),
-            stocks[0].1,
-            stocks[1].1,
-            stocks[2].1,
-            stocks[3].1
-        ));
+async fn requests(args: RequestsArgs, verbose: u8) -> Result<()> {
+    let orders_db = OrdersDb::default();
+    let clients_db = ClientsDb::default();
+    let journal = recover(
+        &args.journal,
+        orders_db.clone(),
+        clients_db.clone(),
+        verbose,
+    )
+    .await?;
+    let input: Box<dyn AsyncRead + Unpin> = match &args.input {
+        Some(path) => Box::new(
+            tokio::fs::File::open(path)
+                .await
+                .map_err(|_| ExchangeError::ProblemWithFile(path.display().to_string()))?,
+        ),
+        None => Box::new(tokio::io::stdin()),
+    };
+    let mut lines = BufReader::new(input).lines();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .map_err(|er| ExchangeError::ProblemWithFile(er.to_string()))?
+    {
+        if let Some(response) = respond(
+            orders_db.clone(),
+            clients_db.clone(),
+            journal.as_ref(),
+            &line,
+        )
+        .await
+        {
+            println!("{}", response.to_json());
+        }
     }
-    println!("{:?} - working time", duration);
     Ok(())
 }
+
+async fn serve(args: ServeArgs, verbose: u8) -> Result<()> {
+    let orders_db = OrdersDb::default();
+    let clients_db = ClientsDb::default();
+    if let Some(clients) = &args.clients {
+        tape::load_clients(clients, orders_db.clone(), clients_db.clone()).await?;
+    }
+    let journal = recover(
+        &args.journal,
+        orders_db.clone(),
+        clients_db.clone(),
+        verbose,
+    )
+    .await?;
+    let listener = tokio::net::TcpListener::bind(&args.addr)
+        .await
+        .map_err(|er| ExchangeError::ProblemWithConnection(er.to_string()))?;
+    if verbose > 0 {
+        eprintln!("listening on {}", args.addr);
+    }
+    if let Some(feed_addr) = &args.feed_addr {
+        let feed = tokio::net::TcpListener::bind(feed_addr)
+            .await
+            .map_err(|er| ExchangeError::ProblemWithConnection(er.to_string()))?;
+        if verbose > 0 {
+            eprintln!("market data on {}", feed_addr);
+        }
+        tokio::spawn(server::serve_feed(feed, orders_db.clone(), verbose));
+    }
+    server::serve(listener, orders_db, clients_db, journal, verbose).await
+}
@@ -0,0 +1,305 @@
+use crate::cli::OutputFormat;
+use crate::tape::parse_header;
+use crate::{Client, ClientsDb, Depth, ExchangeError, Money, OrdersDb, Result};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Writes every client's balance and its amount of every listed ticker,
+/// zero when the client holds none, to `path`. The text report starts with
+/// a `name balance TICKER...` header naming its columns. Clients are
+/// sorted by name and tickers are sorted, so reports of the same tape are
+/// identical.
+pub fn write_result(
+    path: &Path,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    format: OutputFormat,
+) -> Result<()> {
+    let tickers = orders_db.get_tickers();
+    let clients = clients_db.get_all();
+    let mut out = String::new();
+    match format {
+        OutputFormat::Text => {
+            out.push_str("name\tbalance");
+            for ticker in &tickers {
+                out.push_str(&format!("\t{}", ticker));
+            }
+            out.push('\n');
+            for client in &clients {
+                out.push_str(&format!("{}\t{}", client.get_name(), client.get_balance()));
+                for ticker in &tickers {
+                    out.push_str(&format!("\t{}", client.get_amount_of_stock(ticker)));
+                }
+                out.push('\n');
+            }
+        }
+        OutputFormat::Json => {
+            let clients: Vec<_> = clients
+                .iter()
+                .map(|client| {
+                    let stocks: serde_json::Map<_, _> = tickers
+                        .iter()
+                        .map(|ticker| (ticker.clone(), json!(client.get_amount_of_stock(ticker))))
+                        .collect();
+                    json!({
+                        "name": client.get_name(),
+                        "balance": client.get_balance(),
+                        "stocks": stocks,
+                    })
+                })
+                .collect();
+            out = serde_json::to_string_pretty(&clients).unwrap_or_default();
+            out.push('\n');
+        }
+    }
+    File::create(path)
+        .and_then(|mut f| f.write_all(out.as_bytes()))
+        .map_err(|_| ExchangeError::ProblemWithFile(path.display().to_string()))
+}
+
+/// Compares the clients against an expected text report. A header line
+/// names the ticker columns, without one they are the listed tickers in
+/// sorted order. Tickers without a column are expected to be zero.
+/// Returns one message per client and column that differs.
+pub fn compare_result(
+    expected: &Path,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+) -> Result<Vec<String>> {
+    let tickers = orders_db.get_tickers();
+    let content = std::fs::read_to_string(expected)
+        .map_err(|_| ExchangeError::ProblemWithFile(expected.display().to_string()))?;
+    let mut problems = Vec::new();
+    let mut seen = HashSet::new();
+    let mut lines = content.lines().enumerate().peekable();
+    let columns = match lines.peek().and_then(|(_, line)| parse_header(line)) {
+        Some(columns) => {
+            lines.next();
+            columns
+        }
+        None => tickers.clone(),
+    };
+    for column in columns.iter().filter(|column| !tickers.contains(column)) {
+        problems.push(format!(
+            "{}:1: {}: {}",
+            expected.display(),
+            column,
+            ExchangeError::UnknownTicker
+        ));
+    }
+    for (line_number, line) in lines {
+        let mut iter = line.split_whitespace();
+        let name = match iter.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        let values: Vec<&str> = iter.collect();
+        seen.insert(name);
+        let client = match clients_db.get(name) {
+            Some(client) => client,
+            None => {
+                problems.push(format!("{}: missing from the result", name));
+                continue;
+            }
+        };
+        if values.len() > columns.len() + 1 {
+            problems.push(format!(
+                "{}:{}: {}",
+                expected.display(),
+                line_number + 1,
+                ExchangeError::TooManyColumns
+            ));
+        }
+        let balance = values.first().copied().unwrap_or("0");
+        let amounts: HashMap<&str, &str> = columns
+            .iter()
+            .map(String::as_str)
+            .zip(values.iter().skip(1).copied())
+            .collect();
+        let compared = std::iter::once(("balance", balance, client.get_balance())).chain(
+            tickers.iter().map(|ticker| {
+                (
+                    ticker.as_str(),
+                    amounts.get(ticker.as_str()).copied().unwrap_or("0"),
+                    Money::from(*client.get_amount_of_stock(ticker)),
+                )
+            }),
+        );
+        for (column, value, got) in compared {
+            match value.parse::<Money>() {
+                Ok(want) if want == got => (),
+                Ok(want) => problems.push(format!(
+                    "{}: {} expected {}, got {}",
+                    name, column, want, got
+                )),
+                Err(_) => problems.push(format!(
+                    "{}:{}: {}",
+                    expected.display(),
+                    line_number + 1,
+                    ExchangeError::ProblemWithNumber
+                )),
+            }
+        }
+    }
+    let clients = clients_db.get_all();
+    let extra = clients
+        .iter()
+        .map(Client::get_name)
+        .filter(|name| !seen.contains(name));
+    for name in extra {
+        problems.push(format!("{}: not in the expected report", name));
+    }
+    Ok(problems)
+}
+
+/// Depth of one ticker or of every listed ticker, as text or JSON.
+pub fn format_book(
+    orders_db: OrdersDb,
+    ticker: Option<&str>,
+    levels: usize,
+    format: OutputFormat,
+) -> Result<String> {
+    let tickers = match ticker {
+        Some(ticker) => vec![ticker.to_string()],
+        None => orders_db.get_tickers(),
+    };
+    let mut books: Vec<(String, Depth)> = Vec::with_capacity(tickers.len());
+    for ticker in tickers {
+        let book = orders_db
+            .get_book(&ticker)
+            .ok_or(ExchangeError::UnknownTicker)?;
+        let depth = book.lock().unwrap().depth(levels);
+        books.push((ticker, depth));
+    }
+
+    let mut out = String::new();
+    match format {
+        OutputFormat::Text => {
+            for (ticker, depth) in &books {
+                out.push_str(&format!("{}\n", ticker));
+                for level in depth.asks.iter().rev() {
+                    out.push_str(&format!(
+                        "  ask\t{}\t{}\t({} orders)\n",
+                        level.price, level.amount, level.orders
+                    ));
+                }
+                for level in &depth.bids {
+                    out.push_str(&format!(
+                        "  bid\t{}\t{}\t({} orders)\n",
+                        level.price, level.amount, level.orders
+                    ));
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let books: serde_json::Map<_, _> = books
+                .into_iter()
+                .map(|(ticker, depth)| (ticker.to_string(), json!(depth)))
+                .collect();
+            out = serde_json::to_string_pretty(&books).unwrap_or_default();
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExchangeOperation;
+
+    #[tokio::test]
+    async fn test_write_result_zero_fills_tickers() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for ticker in ["A", "B", "C", "D", "E"] {
+            ExchangeOperation::add_ticker(orders_db.clone(), ticker)
+                .await
+                .unwrap();
+        }
+        let mut client = Client::new("C1", 100.into());
+        client
+            .update_stock_balance("E", 7, crate::Operation::Buy)
+            .unwrap();
+        ExchangeOperation::add_client(clients_db.clone(), client)
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join("victorparamonov_test_result.txt");
+        write_result(&path, orders_db, clients_db, OutputFormat::Text).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            written,
+            "name\tbalance\tA\tB\tC\tD\tE\nC1\t100\t0\t0\t0\t0\t7\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_result_and_compare() {
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        for ticker in ["B", "A"] {
+            ExchangeOperation::add_ticker(orders_db.clone(), ticker)
+                .await
+                .unwrap();
+        }
+        for (name, balance) in [("C2", 20), ("C3", 30), ("C1", 10)] {
+            let mut client = Client::new(name, balance.into());
+            client
+                .update_stock_balance("B", 1, crate::Operation::Buy)
+                .unwrap();
+            ExchangeOperation::add_client(clients_db.clone(), client)
+                .await
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join("victorparamonov_test_sorted.txt");
+        write_result(
+            &path,
+            orders_db.clone(),
+            clients_db.clone(),
+            OutputFormat::Text,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            written,
+            "name\tbalance\tA\tB\nC1\t10\t0\t1\nC2\t20\t0\t1\nC3\t30\t0\t1\n"
+        );
+        assert!(compare_result(&path, orders_db.clone(), clients_db.clone())
+            .unwrap()
+            .is_empty());
+
+        // the header puts B first and leaves A out, so A is expected zero
+        std::fs::write(&path, "name balance B\nC1 10 1\nC2 20 1\nC3 30 2\n").unwrap();
+        let problems = compare_result(&path, orders_db.clone(), clients_db.clone()).unwrap();
+        assert_eq!(problems, vec!["C3: B expected 2, got 1"]);
+        std::fs::write(&path, "name balance B X\nC1 10 1\nC2 20 1\nC3 30 1\n").unwrap();
+        let problems = compare_result(&path, orders_db.clone(), clients_db.clone()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].ends_with(":1: X: Ticker is not listed on the exchange"),
+            "{:?}",
+            problems
+        );
+
+        // reports without a header have the listed tickers in sorted order
+
+        std::fs::write(&path, "C1\t10\t0\t1\nC2\t25\t4\t1\nC4\t1\n").unwrap();
+        let problems = compare_result(&path, orders_db, clients_db).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                "C2: balance expected 25, got 20",
+                "C2: A expected 4, got 0",
+                "C4: missing from the result",
+                "C3: not in the expected report",
+            ]
+        );
+    }
+}
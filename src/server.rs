@@ -0,0 +1,590 @@
+use crate::{
+    respond, AuctionReport, ClientsDb, ExchangeError, ExecutionReport, Fill, JournalDb, OrderId,
+    OrderStatus, OrdersDb, Response, ResponseLine, Result,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+
+/// Lines a session may have waiting to be written before it is closed.
+const OUTBOX_CAPACITY: usize = 4096;
+
+/// Where the lines for one session go. A session that does not read them
+/// fast enough fills it up and is closed, the way the feed drops what a
+/// lagging subscriber missed, so it cannot make the server buffer without
+/// limit.
+#[derive(Clone)]
+struct Outbox {
+    sender: mpsc::Sender<String>,
+    overflow: Arc<Notify>,
+}
+
+impl Outbox {
+    fn new(capacity: usize) -> (Self, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let outbox = Outbox {
+            sender,
+            overflow: Arc::default(),
+        };
+        (outbox, receiver)
+    }
+
+    fn send(&self, line: String) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(line) {
+            self.overflow.notify_one();
+        }
+    }
+
+    fn same_session(&self, other: &Outbox) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+}
+
+/// Session that placed each resting or waiting order, so fills and
+/// reports of the order can be sent back to it. Requests run without it
+/// locked; it is only locked to register and notify once a request has
+/// returned. A line for an order its session has not registered yet waits
+/// in `pending` until it does.
+#[derive(Default)]
+struct Owners {
+    /// `None` once the session is gone.
+    sessions: HashMap<OrderId, Option<Outbox>>,
+    pending: HashMap<OrderId, Vec<String>>,
+    /// Orders up to this id were placed before the server started and
+    /// belong to no session.
+    last_order_id_at_start: OrderId,
+}
+
+impl Owners {
+    fn new(last_order_id_at_start: OrderId) -> Self {
+        Owners {
+            last_order_id_at_start,
+            ..Owners::default()
+        }
+    }
+
+    /// Sends what waited for the order, then everything after it, to
+    /// `outbox`.
+    fn register(&mut self, order_id: OrderId, outbox: Outbox) {
+        for line in self.pending.remove(&order_id).unwrap_or_default() {
+            outbox.send(line);
+        }
+        self.sessions.insert(order_id, Some(outbox));
+    }
+
+    fn send(&mut self, order_id: OrderId, line: String) {
+        match self.sessions.get(&order_id) {
+            Some(Some(outbox)) => outbox.send(line),
+            Some(None) => (),
+            None if order_id > self.last_order_id_at_start => {
+                self.pending.entry(order_id).or_default().push(line);
+            }
+            None => (),
+        }
+    }
+
+    /// Forgets an order that left the book. Lines still waiting for its
+    /// session are kept until the session registers it.
+    fn forget_if_gone(&mut self, orders_db: &OrdersDb, order_id: OrderId) {
+        if orders_db.get_order(order_id).is_none() && self.sessions.remove(&order_id).is_some() {
+            self.pending.remove(&order_id);
+        }
+    }
+
+    /// Drops a closed session's outbox, its orders stay known so their
+    /// fills are not kept waiting.
+    fn close(&mut self, outbox: &Outbox) {
+        for owner in self.sessions.values_mut() {
+            if owner
+                .as_ref()
+                .is_some_and(|owner| owner.same_session(outbox))
+            {
+                *owner = None;
+            }
+        }
+    }
+}
+
+/// Accepts order-entry sessions until the listener fails. Every session
+/// sends JSON Lines requests and gets one response line per request, plus
+/// a `fill` line whenever one of its resting orders trades. A session that
+/// stops reading its lines is closed once `OUTBOX_CAPACITY` of them wait.
+pub async fn serve(
+    listener: TcpListener,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    journal: Option<JournalDb>,
+    verbose: u8,
+) -> Result<()> {
+    let owners = Arc::new(Mutex::new(Owners::new(orders_db.get_last_order_id())));
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|er| ExchangeError::ProblemWithConnection(er.to_string()))?;
+        if verbose > 0 {
+            eprintln!("session {} opened", peer);
+        }
+        let orders_db = orders_db.clone();
+        let clients_db = clients_db.clone();
+        let owners = owners.clone();
+        let journal = journal.clone();
+        tokio::spawn(async move {
+            session(stream, orders_db, clients_db, journal, owners).await;
+            if verbose > 0 {
+                eprintln!("session {} closed", peer);
+            }
+        });
+    }
+}
+
+/// Streams market data to every connection as JSON lines, after a first
+/// `subscribed` line carrying the sequence number of the last event
+/// before the subscription. A subscriber that falls behind loses the
+/// oldest events and sees a gap in the sequence numbers.
+pub async fn serve_feed(listener: TcpListener, orders_db: OrdersDb, verbose: u8) -> Result<()> {
+    loop {
+        let (mut stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|er| ExchangeError::ProblemWithConnection(er.to_string()))?;
+        if verbose > 0 {
+            eprintln!("feed {} opened", peer);
+        }
+        let (mut feed, last_sequence) = {
+            let publisher = orders_db.get_publisher();
+            (publisher.subscribe(), publisher.get_last_sequence())
+        };
+        tokio::spawn(async move {
+            let hello = json!({"type": "subscribed", "last_sequence": last_sequence});
+            if stream
+                .write_all(format!("{}\n", hello).as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+            loop {
+                let data = match feed.recv().await {
+                    Ok(data) => data,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let mut line = serde_json::to_string(&data).unwrap_or_default();
+                line.push('\n');
+                if stream.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            if verbose > 0 {
+                eprintln!("feed {} closed", peer);
+            }
+        });
+    }
+}
+
+async fn session(
+    stream: TcpStream,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    journal: Option<JournalDb>,
+    owners: Arc<Mutex<Owners>>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let (outbox, mut receiver) = Outbox::new(OUTBOX_CAPACITY);
+    let writer_task = tokio::spawn(async move {
+        while let Some(mut line) = receiver.recv().await {
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut overflowed = false;
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => line,
+                _ => break,
+            },
+            _ = outbox.overflow.notified() => {
+                overflowed = true;
+                break;
+            }
+        };
+        let mut response = match respond(
+            orders_db.clone(),
+            clients_db.clone(),
+            journal.as_ref(),
+            &line,
+        )
+        .await
+        {
+            Some(response) => response,
+            None => continue,
+        };
+        let mut owners = owners.lock().await;
+        let mut resting = None;
+        match &mut response.response {
+            Response::Execution(report) => {
+                notify_makers(&mut owners, &orders_db, report);
+                if matches!(
+                    report.status,
+                    OrderStatus::New | OrderStatus::Rested | OrderStatus::PartiallyFilled
+                ) {
+                    resting = Some(report.order_id);
+                }
+                // stop orders activated by this one belong to other
+                // sessions, each hears about its own
+                for triggered in std::mem::take(&mut report.triggered) {
+                    notify_triggered(&mut owners, &orders_db, triggered);
+                }
+            }
+            Response::Auction(report) => {
+                notify_auction(&mut owners, &orders_db, report);
+                for triggered in std::mem::take(&mut report.triggered) {
+                    notify_triggered(&mut owners, &orders_db, triggered);
+                }
+            }
+            Response::Cancelled { order } => {
+                owners.forget_if_gone(&orders_db, order.get_id());
+            }
+            _ => (),
+        }
+        outbox.send(response.to_json());
+        // after the response, so fills that came in meanwhile follow it
+        if let Some(order_id) = resting {
+            owners.register(order_id, outbox.clone());
+            owners.forget_if_gone(&orders_db, order_id);
+        }
+    }
+
+    owners.lock().await.close(&outbox);
+    drop(outbox);
+    if overflowed {
+        // the peer is not reading, what is left for it is dropped
+        writer_task.abort();
+    } else {
+        let _ = writer_task.await;
+    }
+}
+
+/// Sends every fill to the session of its maker order and forgets makers
+/// that left the book.
+fn notify_makers(owners: &mut Owners, orders_db: &OrdersDb, report: &ExecutionReport) {
+    for fill in &report.fills {
+        notify_fill(owners, orders_db, fill.maker_order_id, fill);
+    }
+}
+
+/// Sends every auction fill to the sessions of both its orders, as both
+/// were resting.
+fn notify_auction(owners: &mut Owners, orders_db: &OrdersDb, report: &AuctionReport) {
+    for fill in &report.fills {
+        notify_fill(owners, orders_db, fill.maker_order_id, fill);
+        notify_fill(owners, orders_db, fill.taker_order_id, fill);
+    }
+}
+
+fn notify_fill(owners: &mut Owners, orders_db: &OrdersDb, order_id: OrderId, fill: &Fill) {
+    let line = ResponseLine {
+        id: None,
+        response: Response::Fill(fill.clone()),
+    };
+    owners.send(order_id, line.to_json());
+    owners.forget_if_gone(orders_db, order_id);
+}
+
+/// Sends the report of an activated stop order to the session that placed
+/// it and the fills to their makers.
+fn notify_triggered(owners: &mut Owners, orders_db: &OrdersDb, report: ExecutionReport) {
+    notify_makers(owners, orders_db, &report);
+    let order_id = report.order_id;
+    let line = ResponseLine {
+        id: None,
+        response: Response::Execution(report),
+    };
+    owners.send(order_id, line.to_json());
+    owners.forget_if_gone(orders_db, order_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExchangeOperation;
+    use serde_json::Value;
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+
+    struct TestSession {
+        lines: Lines<BufReader<ReadHalf<TcpStream>>>,
+        writer: WriteHalf<TcpStream>,
+    }
+
+    impl TestSession {
+        async fn connect(addr: std::net::SocketAddr) -> Self {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            TestSession {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn send(&mut self, request: Value) -> Value {
+            let line = format!("{}\n", request);
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+            self.recv().await
+        }
+
+        async fn recv(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    async fn start() -> std::net::SocketAddr {
+        start_with_feed().await.0
+    }
+
+    /// Every test trades ticker A.
+    async fn start_with_feed() -> (std::net::SocketAddr, std::net::SocketAddr) {
+        let orders_db = OrdersDb::default();
+        ExchangeOperation::add_ticker(orders_db.clone(), "A")
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            orders_db.clone(),
+            ClientsDb::default(),
+            None,
+            0,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let feed_addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_feed(listener, orders_db, 0));
+        (addr, feed_addr)
+    }
+
+    #[tokio::test]
+    async fn test_sessions_get_acks_and_fills() {
+        let addr = start().await;
+        let mut seller = TestSession::connect(addr).await;
+        let mut buyer = TestSession::connect(addr).await;
+
+        let response = seller
+            .send(json!({"id": 1, "type": "add_client", "name": "S", "stocks": {"A": 10}}))
+            .await;
+        assert_eq!(response, json!({"id": 1, "type": "ok"}));
+        buyer
+            .send(json!({"type": "add_client", "name": "B", "balance": 100}))
+            .await;
+
+        let response = seller
+            .send(json!({"id": 2, "type": "place_order", "client": "S", "side": "sell", "ticker": "A", "price": 5, "amount": 4}))
+            .await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["status"], "rested");
+        let sell_id = response["order_id"].clone();
+
+        let response = buyer
+            .send(json!({"type": "place_order", "client": "B", "side": "buy", "ticker": "A", "price": 5, "amount": 3}))
+            .await;
+        assert_eq!(response["status"], "filled");
+        assert_eq!(response["fills"][0]["amount"], 3);
+
+        let fill = seller.recv().await;
+        assert_eq!(fill["type"], "fill");
+        assert_eq!(fill["maker_order_id"], sell_id);
+        assert_eq!(fill["taker"], "B");
+        assert_eq!(fill["amount"], 3);
+
+        let response = seller
+            .send(json!({"type": "cancel_order", "order_id": sell_id}))
+            .await;
+        assert_eq!(response["type"], "cancelled");
+        assert_eq!(response["order"]["amount"], 1);
+
+        let response = buyer.send(json!({"type": "bad"})).await;
+        assert_eq!(response["type"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_triggered_stop_goes_to_its_session() {
+        let addr = start().await;
+        let mut seller = TestSession::connect(addr).await;
+        let mut stopper = TestSession::connect(addr).await;
+        seller
+            .send(json!({"type": "add_client", "name": "S", "stocks": {"A": 10}}))
+            .await;
+        stopper
+            .send(json!({"type": "add_client", "name": "T", "balance": 100}))
+            .await;
+        seller
+            .send(json!({"type": "place_order", "client": "S", "side": "sell", "ticker": "A", "price": 5, "amount": 4}))
+            .await;
+        let response = stopper
+            .send(json!({"type": "place_order", "client": "T", "side": "buy", "ticker": "A", "order_type": "stop", "stop_price": 5, "amount": 2}))
+            .await;
+        assert_eq!(response["status"], "new");
+        let stop_id = response["order_id"].clone();
+
+        let mut buyer = TestSession::connect(addr).await;
+        buyer
+            .send(json!({"type": "add_client", "name": "B", "balance": 100}))
+            .await;
+        let response = buyer
+            .send(json!({"type": "place_order", "client": "B", "side": "buy", "ticker": "A", "price": 5, "amount": 1}))
+            .await;
+        assert_eq!(response["status"], "filled");
+        assert!(response.get("triggered").is_none());
+
+        let report = stopper.recv().await;
+        assert_eq!(report["type"], "execution");
+        assert_eq!(report["order_id"], stop_id);
+        assert_eq!(report["status"], "filled");
+        assert_eq!(report["fills"][0]["amount"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_auction_fills_go_to_both_sessions() {
+        let addr = start().await;
+        let mut seller = TestSession::connect(addr).await;
+        let mut buyer = TestSession::connect(addr).await;
+        let mut admin = TestSession::connect(addr).await;
+        seller
+            .send(json!({"type": "add_client", "name": "S", "stocks": {"A": 10}}))
+            .await;
+        buyer
+            .send(json!({"type": "add_client", "name": "B", "balance": 100}))
+            .await;
+        admin
+            .send(json!({"type": "set_trading_status", "ticker": "A", "status": "auction"}))
+            .await;
+        let response = seller
+            .send(json!({"type": "place_order", "client": "S", "side": "sell", "ticker": "A", "price": 5, "amount": 4}))
+            .await;
+        assert_eq!(response["status"], "rested");
+        let response = buyer
+            .send(json!({"type": "place_order", "client": "B", "side": "buy", "ticker": "A", "price": 6, "amount": 3}))
+            .await;
+        assert_eq!(response["status"], "rested");
+
+        let response = admin
+            .send(json!({"type": "query_auction", "ticker": "A"}))
+            .await;
+        assert_eq!(
+            response,
+            json!({"type": "indicative", "ticker": "A", "price": "5", "volume": 3,
+                   "imbalance": 1, "imbalance_side": "sell"})
+        );
+        let response = admin
+            .send(json!({"type": "end_auction", "ticker": "A"}))
+            .await;
+        assert_eq!(response["type"], "auction");
+        assert_eq!(response["fills"].as_array().unwrap().len(), 1);
+
+        for session in [&mut seller, &mut buyer] {
+            let fill = session.recv().await;
+            assert_eq!(fill["type"], "fill");
+            assert_eq!(fill["price"], "5");
+            assert_eq!(fill["amount"], 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_sessions() {
+        let addr = start().await;
+        let mut admin = TestSession::connect(addr).await;
+        admin
+            .send(json!({"type": "add_client", "name": "S", "stocks": {"A": 100}}))
+            .await;
+        admin
+            .send(json!({"type": "place_order", "client": "S", "side": "sell", "ticker": "A", "price": 1, "amount": 100}))
+            .await;
+
+        let mut tasks = Vec::new();
+        for n in 0..10 {
+            tasks.push(tokio::spawn(async move {
+                let mut session = TestSession::connect(addr).await;
+                let name = format!("B{}", n);
+                session
+                    .send(json!({"type": "add_client", "name": name, "balance": 10}))
+                    .await;
+                for _ in 0..5 {
+                    let response = session
+                        .send(json!({"type": "place_order", "client": name, "side": "buy", "ticker": "A", "price": 1, "amount": 2}))
+                        .await;
+                    assert_eq!(response["status"], "filled");
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut fills = 0;
+        while fills < 50 {
+            let fill = admin.recv().await;
+            assert_eq!(fill["type"], "fill");
+            fills += 1;
+        }
+        let response = admin
+            .send(json!({"type": "query_client", "name": "S"}))
+            .await;
+        assert_eq!(response["balance"], "100");
+        assert_eq!(response["stocks"]["A"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_market_data_feed() {
+        let (addr, feed_addr) = start_with_feed().await;
+        let mut feed = TestSession::connect(feed_addr).await;
+        assert_eq!(
+            feed.recv().await,
+            json!({"type": "subscribed", "last_sequence": 0})
+        );
+        let mut session = TestSession::connect(addr).await;
+        session
+            .send(json!({"type": "add_client", "name": "S", "balance": 10, "stocks": {"A": 10}}))
+            .await;
+        session
+            .send(json!({"type": "place_order", "client": "S", "side": "sell", "ticker": "A", "price": 5, "amount": 4, "self_trade_prevention": "cancel_oldest"}))
+            .await;
+        session
+            .send(json!({"type": "place_order", "client": "S", "side": "buy", "ticker": "A", "price": 1, "amount": 2}))
+            .await;
+
+        let event = feed.recv().await;
+        assert_eq!(event["sequence"], 1);
+        assert_eq!(event["type"], "order_added");
+        assert_eq!(event["side"], "sell");
+        let event = feed.recv().await;
+        assert_eq!(event["sequence"], 2);
+        assert_eq!(event["type"], "order_added");
+        assert_eq!(event["side"], "buy");
+        assert_eq!(event["price"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_full_outbox_closes_the_session() {
+        let (outbox, mut receiver) = Outbox::new(1);
+        outbox.send("1".to_string());
+        outbox.send("2".to_string());
+        // the second line did not fit, the session is told to close
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            outbox.overflow.notified(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(receiver.recv().await.as_deref(), Some("1"));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{
+    Client, ClientsDb, ExchangeError, ExchangeOperation, Money, Operation, Order, OrderStatus,
+    OrdersDb, Price, Result,
+};
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader, Lines},
+};
+
+/// Counters of one replayed orders tape.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub orders: usize,
+    pub rejected: usize,
+    pub fills: usize,
+}
+
+/// Ticker of the n-th amount column when the clients tape has no header.
+pub fn default_tickers() -> Vec<String> {
+    (b'A'..=b'Z').map(|c| (c as char).to_string()).collect()
+}
+
+/// `name balance TICKER...`, names the amount columns of the clients tape.
+/// Returns `None` when the line is not a header.
+pub fn parse_header(line: &str) -> Option<Vec<String>> {
+    let mut iter = line.split_whitespace();
+    match iter.next() {
+        Some(first) if first.eq_ignore_ascii_case("name") => {
+            iter.next()?;
+            Some(iter.map(str::to_string).collect())
+        }
+        _ => None,
+    }
+}
+
+/// `name balance amount...`, the n-th amount column is `tickers[n]`.
+/// Returns `None` for lines without a name or balance.
+pub fn parse_client(line: &str, tickers: &[String]) -> Result<Option<Client>> {
+    let mut iter = line.split_whitespace();
+    let name = match iter.next() {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let balance = match iter.next() {
+        Some(value) => value.parse::<Money>()?,
+        None => return Ok(None),
+    };
+    let mut client = Client::new(name, balance);
+    for (column, value) in iter.enumerate() {
+        let ticker = tickers.get(column).ok_or(ExchangeError::TooManyColumns)?;
+        let amount = value
+            .parse::<u32>()
+            .map_err(|_| ExchangeError::ProblemWithNumber)?;
+        client.update_stock_balance(ticker, amount, Operation::Buy)?;
+    }
+    Ok(Some(client))
+}
+
+/// `name b|s ticker price amount`. Returns `None` for incomplete lines.
+pub fn parse_order(line: &str) -> Result<Option<Order>> {
+    let mut iter = line.split_whitespace();
+    let (name, operation, ticker, price, amount) = match (
+        iter.next(),
+        iter.next(),
+        iter.next(),
+        iter.next(),
+        iter.next(),
+    ) {
+        (Some(name), Some(operation), Some(ticker), Some(price), Some(amount)) => {
+            (name, operation, ticker, price, amount)
+        }
+        _ => return Ok(None),
+    };
+    let operation =
+        Operation::from_str(operation).map_err(|_| ExchangeError::ProblemWithParsingOperation)?;
+    let price = price.parse::<Price>()?;
+    let amount = amount
+        .parse::<u32>()
+        .map_err(|_| ExchangeError::ProblemWithNumber)?;
+    Ok(Some(Order::new(name, operation, ticker, price, amount)))
+}
+
+async fn read_lines(path: &Path) -> Result<Lines<BufReader<File>>> {
+    let file = File::open(path)
+        .await
+        .map_err(|_| ExchangeError::ProblemWithFile(path.display().to_string()))?;
+    Ok(BufReader::new(file).lines())
+}
+
+async fn next_line(lines: &mut Lines<BufReader<File>>, path: &Path) -> Result<Option<String>> {
+    lines
+        .next_line()
+        .await
+        .map_err(|_| ExchangeError::ProblemWithFile(path.display().to_string()))
+}
+
+/// Parsed clients tape: the tickers it lists and every client line with
+/// its line number.
+pub struct ClientsTape {
+    pub tickers: Vec<String>,
+    pub clients: Vec<(usize, Result<Client>)>,
+}
+
+/// Reads the clients tape. With a header line every ticker of the header is
+/// listed, otherwise the columns are 'A', 'B', ... and only the ones some
+/// client has a column for are listed.
+pub async fn read_clients(path: &Path) -> Result<ClientsTape> {
+    let mut lines = read_lines(path).await?;
+    let defaults = default_tickers();
+    let mut header: Option<Vec<String>> = None;
+    let mut clients = Vec::new();
+    let mut columns = 0;
+    let mut line_number = 0;
+    while let Some(line) = next_line(&mut lines, path).await? {
+        line_number += 1;
+        if header.is_none() && clients.is_empty() {
+            if let Some(tickers) = parse_header(&line) {
+                header = Some(tickers);
+                continue;
+            }
+        }
+        match parse_client(&line, header.as_deref().unwrap_or(&defaults)) {
+            Ok(Some(client)) => {
+                columns = columns.max(client.get_stocks().len());
+                clients.push((line_number, Ok(client)));
+            }
+            Ok(None) => (),
+            Err(er) => clients.push((line_number, Err(er))),
+        }
+    }
+    let tickers = match header {
+        Some(tickers) => tickers,
+        None => defaults.into_iter().take(columns).collect(),
+    };
+    Ok(ClientsTape { tickers, clients })
+}
+
+/// Registers every client of the tape and lists its tickers. Lines that
+/// do not parse and clients already registered are skipped and reported as
+/// `validate` reports them. Returns the number of clients added.
+pub async fn load_clients(
+    path: &Path,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+) -> Result<usize> {
+    let tape = read_clients(path).await?;
+    for ticker in &tape.tickers {
+        let _ = ExchangeOperation::add_ticker(orders_db.clone(), ticker).await;
+    }
+    let mut count = 0;
+    for (line_number, client) in tape.clients {
+        let added = match client {
+            Ok(client) => ExchangeOperation::add_client(clients_db.clone(), client).await,
+            Err(er) => Err(er),
+        };
+        match added {
+            Ok(()) => count += 1,
+            Err(er) => eprintln!("{}:{}: skipped: {}", path.display(), line_number, er),
+        }
+    }
+    Ok(count)
+}
+
+pub async fn replay_orders(
+    path: &Path,
+    orders_db: OrdersDb,
+    clients_db: ClientsDb,
+    verbose: u8,
+) -> Result<ReplayStats> {
+    let mut lines = read_lines(path).await?;
+    let mut stats = ReplayStats::default();
+    let mut line_number = 0;
+    while let Some(line) = next_line(&mut lines, path).await? {
+        line_number += 1;
+        let order = match parse_order(&line)? {
+            Some(order) => order,
+            None => continue,
+        };
+        stats.orders += 1;
+        match ExchangeOperation::operate(orders_db.clone(), clients_db.clone(), order).await {
+            Ok(report) => {
+                stats.fills += report.fills.len();
+                if let OrderStatus::Rejected(reason) = report.status {
+                    stats.rejected += 1;
+                    if verbose > 1 {
+                        eprintln!("line {}: rejected: {}", line_number, reason);
+                    }
+                }
+            }
+            Err(er) => {
+                eprintln!("line {}: error {:?}", line_number, er);
+            }
+        };
+    }
+    Ok(stats)
+}
+
+/// Parses both tapes without matching and checks that every order names a
+/// known client and ticker and has a non-zero price and amount.
+/// Returns one message per bad line.
+pub async fn validate(clients_path: &Path, orders_path: &Path) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let mut clients = HashSet::new();
+    let tape = read_clients(clients_path).await?;
+    let tickers: HashSet<String> = tape.tickers.into_iter().collect();
+    for (line_number, client) in tape.clients {
+        match client {
+            Ok(client) => {
+                if !clients.insert(client.get_name().to_string()) {
+                    problems.push(format!(
+                        "{}:{}: {}",
+                        clients_path.display(),
+                        line_number,
+                        ExchangeError::UserAlreadyRegistered
+                    ));
+                }
+            }
+            Err(er) => problems.push(format!(
+                "{}:{}: {}",
+                clients_path.display(),
+                line_number,
+                er
+            )),
+        }
+    }
+
+    let mut lines = read_lines(orders_path).await?;
+    let mut line_number = 0;
+    while let Some(line) = next_line(&mut lines, orders_path).await? {
+        line_number += 1;
+        let order = match parse_order(&line) {
+            Ok(Some(order)) => order,
+            Ok(None) => continue,
+            Err(er) => {
+                problems.push(format!("{}:{}: {}", orders_path.display(), line_number, er));
+                continue;
+            }
+        };
+        let problem = if !clients.contains(order.get_client()) {
+            Some(ExchangeError::UnknownUser)
+        } else if !tickers.contains(order.get_ticker()) {
+            Some(ExchangeError::UnknownTicker)
+        } else if order.get_amount() == 0 {
+            Some(ExchangeError::ZeroAmount)
+        } else if order.get_price().is_zero() {
+            Some(ExchangeError::ZeroPrice)
+        } else {
+            None
+        };
+        if let Some(er) = problem {
+            problems.push(format!("{}:{}: {}", orders_path.display(), line_number, er));
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client() {
+        let client = parse_client("C1\t1000\t130\t240", &default_tickers())
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.get_name(), "C1");
+        assert_eq!(client.get_balance(), 1000.into());
+        assert_eq!(client.get_amount_of_stock("A"), &130);
+        assert_eq!(client.get_amount_of_stock("B"), &240);
+        assert!(parse_client("C1", &default_tickers()).unwrap().is_none());
+        assert_eq!(
+            parse_client("C1 10 x", &default_tickers()).err(),
+            Some(ExchangeError::ProblemWithNumber)
+        );
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let tickers = parse_header("name\tbalance\tAAPL\tMSFT\tGOOG\tAMZN\tTSLA").unwrap();
+        assert_eq!(tickers, vec!["AAPL", "MSFT", "GOOG", "AMZN", "TSLA"]);
+        assert!(parse_header("C1\t1000\t130").is_none());
+
+        let client = parse_client("C1 1000 1 2 3 4 5", &tickers)
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.get_amount_of_stock("TSLA"), &5);
+        assert_eq!(client.get_amount_of_stock("AAPL"), &1);
+        let client = parse_client("C2 1000 1", &tickers).unwrap().unwrap();
+        assert_eq!(client.get_amount_of_stock("MSFT"), &0);
+        assert_eq!(
+            parse_client("C1 1000 1 2 3 4 5 6", &tickers).err(),
+            Some(ExchangeError::TooManyColumns)
+        );
+    }
+
+    #[test]
+    fn test_parse_order() {
+        let order = parse_order("C8\tb\tC\t15\t4").unwrap().unwrap();
+        assert_eq!(order.get_client(), "C8");
+        assert_eq!(order.get_operation(), Operation::Buy);
+        assert_eq!(order.get_ticker(), "C");
+        assert_eq!(order.get_price(), 15.into());
+        assert_eq!(order.get_amount(), 4);
+        let order = parse_order("C8 s C 15.25 4").unwrap().unwrap();
+        assert_eq!(order.get_price(), "15.25".parse().unwrap());
+        assert!(parse_order("C8 b C 15").unwrap().is_none());
+        assert_eq!(
+            parse_order("C8 x C 15 4").err(),
+            Some(ExchangeError::ProblemWithParsingOperation)
+        );
+        assert_eq!(
+            parse_order("C8 b C 15 -4").err(),
+            Some(ExchangeError::ProblemWithNumber)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_clients_skips_bad_lines() {
+        let dir = std::env::temp_dir();
+        let clients_path = dir.join("victorparamonov_test_load_clients.txt");
+        let orders_path = dir.join("victorparamonov_test_load_orders.txt");
+        std::fs::write(&clients_path, "C1 10 1\nC2 x 1\nC3 30 3\nC1 40 4\n").unwrap();
+        std::fs::write(&orders_path, "").unwrap();
+        let orders_db = OrdersDb::default();
+        let clients_db = ClientsDb::default();
+        let count = load_clients(&clients_path, orders_db, clients_db.clone())
+            .await
+            .unwrap();
+        let problems = validate(&clients_path, &orders_path).await.unwrap();
+        std::fs::remove_file(&clients_path).unwrap();
+        std::fs::remove_file(&orders_path).unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(clients_db.len(), 2);
+        assert_eq!(clients_db.get("C1").unwrap().get_balance(), 10.into());
+        assert!(clients_db.contains("C3"));
+        assert_eq!(problems.len(), 2);
+        assert!(
+            problems[0].ends_with(":2: Problem with parsing of input numbers"),
+            "{:?}",
+            problems
+        );
+        assert!(
+            problems[1].ends_with(":4: The provided client with that username already registered"),
+            "{:?}",
+            problems
+        );
+    }
+}